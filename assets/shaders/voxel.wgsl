@@ -0,0 +1,65 @@
+#import bevy_pbr::mesh_view_bind_group
+#import bevy_pbr::mesh_struct
+
+[[group(1), binding(0)]]
+var atlas_texture: texture_2d<f32>;
+[[group(1), binding(1)]]
+var atlas_sampler: sampler;
+
+[[group(2), binding(0)]]
+var<uniform> mesh: Mesh;
+
+struct Vertex {
+    [[location(0)]] position: vec3<f32>;
+    [[location(1)]] normal: vec3<f32>;
+    [[location(2)]] uv: vec2<f32>;
+    [[location(3)]] atlas_tile: vec4<f32>;
+    [[location(4)]] color: vec4<f32>;
+};
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] world_normal: vec3<f32>;
+    [[location(1)]] uv: vec2<f32>;
+    [[location(2)]] atlas_tile: vec4<f32>;
+    [[location(3)]] color: vec4<f32>;
+};
+
+[[stage(vertex)]]
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = view.view_proj * mesh.model * vec4<f32>(vertex.position, 1.0);
+    out.world_normal = mat3x3<f32>(
+        mesh.inverse_transpose_model[0].xyz,
+        mesh.inverse_transpose_model[1].xyz,
+        mesh.inverse_transpose_model[2].xyz
+    ) * vertex.normal;
+    out.uv = vertex.uv;
+    out.atlas_tile = vertex.atlas_tile;
+    out.color = vertex.color;
+    return out;
+}
+
+let PI: f32 = 3.141592653589793;
+
+[[stage(fragment)]]
+fn fragment(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    // Uvs are in blocks, wrapping them keeps merged quads repeating the texture of a single block
+    let tile_uv = clamp(fract(in.uv), vec2<f32>(0.001), vec2<f32>(0.999));
+    let color = textureSample(atlas_texture, atlas_sampler, in.atlas_tile.xy + tile_uv * in.atlas_tile.zw);
+    // Clear texels of see-through blocks are left out entirely so they don't hide the faces behind them in the depth buffer
+    if (color.a < 0.02) {
+        discard;
+    }
+
+    // Vertex colours carry the baked block light in rgb and skylight in alpha, both darkened by ambient occlusion.
+    // The scene lights only reach as far as the skylight does, block light adds on top
+    let normal = normalize(in.world_normal);
+    var sky_light = lights.ambient_color.rgb;
+    for (var i: u32 = 0u; i < lights.n_directional_lights; i = i + 1u) {
+        let directional = lights.directional_lights[i];
+        sky_light = sky_light + directional.color.rgb * max(dot(normal, directional.direction_to_light), 0.0) / PI;
+    }
+    let light = sky_light * in.color.a + in.color.rgb;
+    return vec4<f32>(color.rgb * light, color.a);
+}
@@ -1,10 +1,9 @@
-use std::cell::RefMut;
-use std::collections::HashMap;
 use bevy::prelude::*;
 use crate::{App, GameState, SystemSet};
 use crate::game::player::setup_player;
 use crate::game::world::world::*;
 
+mod physics;
 mod player;
 mod world;
 
@@ -15,15 +14,22 @@ impl Plugin for GamePlugin {
         app.add_system_set(SystemSet::on_enter(GameState::Game)
             .with_system(setup_game).with_system(setup_world));
         app.add_system_set(SystemSet::on_update(GameState::Game)
-            .with_system(player::update_controller).with_system(update_world).with_system(load_chunks));
+            .with_system(player::update_controller).with_system(player::update_block_interaction)
+            .with_system(update_world).with_system(load_chunks).with_system(toggle_meshing_mode).with_system(world_diagnostics)
+            .with_system(world::time::advance_world_time).with_system(world::time::time_controls).with_system(world::time::update_sun));
+        app.add_plugin(MaterialPlugin::<world::material::VoxelMaterial>::default());
+        app.add_startup_system(setup_world_diagnostics);
+        app.add_system_to_stage(CoreStage::Last, save_world);
         app.init_resource::<world::world::World>();
+        app.init_resource::<WorldSettings>();
+        app.init_resource::<world::time::WorldTime>();
     }
 }
 
 fn setup_game(
-    mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>
+    commands: Commands,
+    meshes: ResMut<Assets<Mesh>>,
+    materials: ResMut<Assets<StandardMaterial>>
 ) {
     setup_player(commands, meshes, materials);
 }
\ No newline at end of file
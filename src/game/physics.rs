@@ -0,0 +1,229 @@
+use bevy::prelude::*;
+use crate::game::world::block::BlockId;
+use crate::game::world::world::World;
+
+/* Small gap kept between a body and the voxel it rests against so it isn't counted as overlapping */
+const SKIN: f32 = 0.001;
+/* Longest distance moved in one collision step, keeps fast bodies from tunnelling through single voxels */
+const MAX_STEP: f32 = 0.5;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3
+}
+
+impl Aabb {
+    pub fn from_center(center: Vec3, half_extents: Vec3) -> Self {
+        Self {
+            min: center - half_extents,
+            max: center + half_extents
+        }
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) / 2.
+    }
+
+    pub fn translated(&self, offset: Vec3) -> Self {
+        Self {
+            min: self.min + offset,
+            max: self.max + offset
+        }
+    }
+
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        self.min.cmplt(other.max).all() && other.min.cmplt(self.max).all()
+    }
+}
+
+pub struct SweepResult {
+    pub aabb: Aabb,
+    /* Which axes the motion was stopped on */
+    pub blocked: [bool; 3],
+    pub on_ground: bool
+}
+
+impl World {
+    /* Boxes of the voxel at a position that bodies collide with, following the block's model. Unloaded chunks count as a
+       solid cube so bodies don't fall out of the world before the terrain arrives */
+    pub fn collision_boxes(&self, position: (i32, i32, i32)) -> Vec<Aabb> {
+        let origin = Vec3::new(position.0 as f32, position.1 as f32, position.2 as f32);
+        let block = match self.get_voxel(position) {
+            Some(voxel) => voxel.block,
+            None => return vec![Aabb { min: origin, max: origin + Vec3::ONE }]
+        };
+        if !self.blocks.is_solid(block) {
+            return Vec::new();
+        }
+        let model = self.blocks.model(block);
+        let connections = model.connections(&self.blocks, |facing| {
+            let offset = facing.offset();
+            self.get_voxel((position.0 + offset.0, position.1 + offset.1, position.2 + offset.2)).map_or(BlockId::AIR, |voxel| voxel.block)
+        });
+        model.boxes(connections).into_iter()
+            .map(|model_box| Aabb { min: origin + Vec3::from(model_box.min), max: origin + Vec3::from(model_box.max) })
+            .collect()
+    }
+
+    /* Every collision box overlapping the box */
+    pub fn colliders(&self, aabb: &Aabb) -> Vec<Aabb> {
+        let min = aabb.min.floor();
+        let max = (aabb.max - Vec3::splat(SKIN)).floor();
+        let mut colliders = Vec::new();
+        for x in min.x as i32..=max.x as i32 {
+            for y in min.y as i32..=max.y as i32 {
+                for z in min.z as i32..=max.z as i32 {
+                    colliders.extend(self.collision_boxes((x, y, z)).into_iter().filter(|collider| collider.intersects(aabb)));
+                }
+            }
+        }
+        colliders
+    }
+
+    pub fn collides(&self, aabb: &Aabb) -> bool {
+        !self.colliders(aabb).is_empty()
+    }
+
+    /* Moves a box through the world one axis at a time, vertical first, stepping up ledges no taller than `step_height` */
+    pub fn sweep_aabb(&self, aabb: Aabb, motion: Vec3, step_height: f32) -> SweepResult {
+        let steps = (motion.abs().max_element() / MAX_STEP).ceil().max(1.);
+        let step = motion / steps;
+        let mut result = SweepResult {
+            aabb,
+            blocked: [false; 3],
+            on_ground: false
+        };
+
+        for _ in 0..steps as i32 {
+            let (aabb, blocked_y) = self.move_axis(result.aabb, 1, step.y);
+            result.aabb = aabb;
+            if blocked_y {
+                result.blocked[1] = true;
+                result.on_ground |= step.y < 0.;
+            }
+
+            for axis in [0, 2] {
+                let (aabb, blocked) = self.move_axis(result.aabb, axis, step[axis]);
+                if !blocked {
+                    result.aabb = aabb;
+                    continue;
+                }
+
+                let stepped = if result.on_ground { self.try_step_up(result.aabb, axis, step[axis], step_height) } else { None };
+                match stepped {
+                    Some(aabb) => result.aabb = aabb,
+                    None => {
+                        result.aabb = aabb;
+                        result.blocked[axis] = true;
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    fn move_axis(&self, aabb: Aabb, axis: usize, distance: f32) -> (Aabb, bool) {
+        if distance == 0. {
+            return (aabb, false);
+        }
+        let mut offset = Vec3::ZERO;
+        offset[axis] = distance;
+        let mut moved = aabb.translated(offset);
+        let colliders = self.colliders(&moved);
+        if colliders.is_empty() {
+            return (moved, false);
+        }
+
+        // Snap back against the nearest face of the boxes that were entered
+        let correction = if distance > 0. {
+            colliders.iter().map(|collider| collider.min[axis]).fold(f32::INFINITY, f32::min) - SKIN - moved.max[axis]
+        } else {
+            colliders.iter().map(|collider| collider.max[axis]).fold(f32::NEG_INFINITY, f32::max) + SKIN - moved.min[axis]
+        };
+        let mut correction_offset = Vec3::ZERO;
+        correction_offset[axis] = correction;
+        moved = moved.translated(correction_offset);
+        if self.collides(&moved) {
+            return (aabb, true);
+        }
+        (moved, true)
+    }
+
+    fn try_step_up(&self, aabb: Aabb, axis: usize, distance: f32, step_height: f32) -> Option<Aabb> {
+        if step_height <= 0. {
+            return None;
+        }
+        let mut offset = Vec3::ZERO;
+        offset[axis] = distance;
+        let moved = aabb.translated(offset);
+
+        // Rise to the top of the ledge in front, as long as it is within the step height and there is headroom
+        let ledge = self.colliders(&moved).iter().map(|collider| collider.max.y).fold(f32::NEG_INFINITY, f32::max) + SKIN;
+        let rise = ledge - moved.min.y;
+        if rise <= 0. || rise > step_height + SKIN {
+            return None;
+        }
+        let raised = moved.translated(Vec3::new(0., rise, 0.));
+        if self.collides(&aabb.translated(Vec3::new(0., rise, 0.))) || self.collides(&raised) {
+            return None;
+        }
+        Some(raised)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::world::block::test_blocks::{SLAB, STONE};
+
+    /* Stone floor at y = 0 with the given blocks on top of it */
+    fn world_with(blocks: &[((i32, i32, i32), BlockId)]) -> World {
+        let floor = (0..8).flat_map(|x| (0..3).map(move |z| ((x, 0, z), STONE)));
+        World::with_voxels(&floor.chain(blocks.iter().copied()).collect::<Vec<_>>())
+    }
+
+    fn body(center: Vec3) -> Aabb {
+        Aabb::from_center(center, Vec3::new(0.4, 1., 0.4))
+    }
+
+    #[test]
+    fn falling_body_lands_on_the_ground() {
+        let world = world_with(&[]);
+        let result = world.sweep_aabb(body(Vec3::new(2.5, 4., 1.5)), Vec3::new(0., -5., 0.), 0.);
+        assert!(result.on_ground);
+        assert!(result.blocked[1]);
+        assert!((result.aabb.min.y - 1.).abs() < 0.01);
+    }
+
+    #[test]
+    fn wall_blocks_sideways_motion() {
+        let world = world_with(&[((4, 1, 1), STONE), ((4, 2, 1), STONE)]);
+        let result = world.sweep_aabb(body(Vec3::new(3., 2.01, 1.5)), Vec3::new(1., -0.1, 0.), 0.5);
+        assert!(result.blocked[0]);
+        assert!((result.aabb.max.x - 4.).abs() < 0.01);
+    }
+
+    #[test]
+    fn bodies_step_up_half_ledges_but_not_full_ones() {
+        let start = body(Vec3::new(3., 2.01, 1.5));
+        let motion = Vec3::new(1., -0.1, 0.);
+
+        let slab = world_with(&[((4, 1, 1), SLAB)]).sweep_aabb(start, motion, 0.5);
+        assert!(!slab.blocked[0]);
+        assert!((slab.aabb.min.y - 1.5).abs() < 0.01);
+        assert!((slab.aabb.center().x - 4.).abs() < 0.01);
+
+        let full = world_with(&[((4, 1, 1), STONE)]).sweep_aabb(start, motion, 0.5);
+        assert!(full.blocked[0]);
+        assert!((full.aabb.min.y - 1.).abs() < 0.01);
+    }
+
+    #[test]
+    fn bodies_only_step_up_where_there_is_headroom() {
+        let world = world_with(&[((4, 1, 1), SLAB), ((4, 3, 1), STONE)]);
+        let result = world.sweep_aabb(body(Vec3::new(3., 2.01, 1.5)), Vec3::new(1., -0.1, 0.), 0.5);
+        assert!(result.blocked[0]);
+        assert!((result.aabb.min.y - 1.).abs() < 0.01);
+    }
+}
@@ -1,7 +1,11 @@
-use std::fmt::format;
+use bevy::input::mouse::MouseWheel;
 use bevy::prelude::*;
-use crate::shape::Cube;
+use bevy::render::mesh::PrimitiveTopology;
 use crate::{MouseMotion, vec3};
+use crate::game::physics::Aabb;
+use crate::game::world::block::BlockId;
+use crate::game::world::model::Facing;
+use crate::game::world::world::World;
 
 #[derive(Bundle)]
 struct PlayerBundle {
@@ -11,12 +15,34 @@ struct PlayerBundle {
 }
 
 #[derive(Component)]
-struct PlayerCamera;
+pub struct PlayerCamera;
+
+const PLAYER_HALF_EXTENTS: [f32; 3] = [0.4, 1., 0.4];
+const WALK_SPEED: f32 = 5.;
+const SPRINT_SPEED: f32 = 8.;
+const GRAVITY: f32 = 28.;
+const TERMINAL_VELOCITY: f32 = 50.;
+const JUMP_VELOCITY: f32 = 8.5;
+const STEP_HEIGHT: f32 = 1.;
+
+/* Walking collides with the terrain and falls, flying moves freely through everything */
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MovementMode {
+    Walking,
+    Flying
+}
 
 #[derive(Component)]
 pub struct PlayerController {
     pitch: f32,
-    yaw: f32
+    yaw: f32,
+    selected_block: BlockId,
+    /* How far past the player voxels can be broken or placed */
+    reach: f32,
+    pub mode: MovementMode,
+    pub auto_step: bool,
+    velocity: Vec3,
+    on_ground: bool
 }
 
 impl Default for PlayerController {
@@ -24,10 +50,26 @@ impl Default for PlayerController {
         Self {
             pitch: 0.,
             yaw: 0.,
+            selected_block: BlockId(1),
+            reach: 8.,
+            mode: MovementMode::Flying,
+            auto_step: true,
+            velocity: Vec3::ZERO,
+            on_ground: false
         }
     }
 }
 
+fn player_aabb(position: Vec3) -> Aabb {
+    Aabb::from_center(position, Vec3::from(PLAYER_HALF_EXTENTS))
+}
+
+/* Outline drawn around the voxel the player is looking at */
+#[derive(Component)]
+pub struct BlockHighlight;
+
+type HighlightFilter = (With<BlockHighlight>, Without<PlayerController>);
+
 /* Setups a player entity and adds a pbr bundle as a component, then adds a camera as a child */
 pub fn setup_player(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>, mut materials: ResMut<Assets<StandardMaterial>>) {
     commands.spawn_bundle(
@@ -43,18 +85,61 @@ pub fn setup_player(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>, mu
         parent.spawn_bundle(PerspectiveCameraBundle {
             transform: Transform::identity().with_translation(vec3(0., 3., 10.)),
             ..default()
-        });
+        }).insert(PlayerCamera);
     });
+
+    commands.spawn_bundle(PbrBundle {
+        mesh: meshes.add(create_highlight_mesh()),
+        material: materials.add(StandardMaterial {
+            base_color: Color::BLACK,
+            unlit: true,
+            ..default()
+        }),
+        visibility: Visibility { is_visible: false },
+        ..default()
+    }).insert(BlockHighlight);
+}
+
+/* A line list tracing the edges of a unit cube, pushed out slightly so it doesn't fight with the voxel faces */
+fn create_highlight_mesh() -> Mesh {
+    const MIN: f32 = -0.005;
+    const MAX: f32 = 1.005;
+    let corners = [
+        [MIN, MIN, MIN], [MAX, MIN, MIN], [MAX, MIN, MAX], [MIN, MIN, MAX],
+        [MIN, MAX, MIN], [MAX, MAX, MIN], [MAX, MAX, MAX], [MIN, MAX, MAX],
+    ];
+    let edges = [
+        (0, 1), (1, 2), (2, 3), (3, 0),
+        (4, 5), (5, 6), (6, 7), (7, 4),
+        (0, 4), (1, 5), (2, 6), (3, 7),
+    ];
+    let vertices: Vec<[f32; 3]> = edges.iter().flat_map(|(a, b)| [corners[*a], corners[*b]]).collect();
+
+    let mut mesh = Mesh::new(PrimitiveTopology::LineList);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0., 1., 0.]; vertices.len()]);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, vec![[0., 0.]; vertices.len()]);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, vertices);
+    mesh
 }
 
 pub fn update_controller(
     time: Res<Time>,
     inputs: Res<Input<KeyCode>>,
+    world: Res<World>,
     mut mouse: EventReader<MouseMotion>,
     mut query: Query<(&mut Transform, &mut PlayerController)>
 ) {
     let (mut transform, mut controller): (Mut<Transform>, Mut<PlayerController>) = query.single_mut();
 
+    if inputs.just_pressed(KeyCode::F) {
+        controller.mode = match controller.mode {
+            MovementMode::Walking => MovementMode::Flying,
+            MovementMode::Flying => MovementMode::Walking
+        };
+        controller.velocity = Vec3::ZERO;
+        controller.on_ground = false;
+    }
+
     let mut movement = Vec3::new(0.,0.,0.);
 
     if inputs.pressed(KeyCode::W) { movement.z += 1. }
@@ -69,6 +154,36 @@ pub fn update_controller(
         transform.rotation = Quat::from_euler(EulerRot::XYZ, 0., controller.pitch.to_radians(), 0.) * Quat::from_euler(EulerRot::XYZ, controller.yaw.to_radians(), 0., 0.);
     }
 
+    match controller.mode {
+        MovementMode::Walking => walk(&time, &inputs, &world, movement, &mut transform, &mut controller),
+        MovementMode::Flying => fly(&time, &inputs, movement, &mut transform)
+    }
+}
+
+/* Applies gravity and jumping, then sweeps the player's box through the terrain */
+fn walk(time: &Time, inputs: &Input<KeyCode>, world: &World, movement: Vec3, transform: &mut Transform, controller: &mut PlayerController) {
+    let mut direction = transform.forward() * movement.z + transform.right() * movement.x;
+    direction.y = 0.;
+    direction = direction.normalize_or_zero();
+
+    let speed = if inputs.pressed(KeyCode::LControl) { SPRINT_SPEED } else { WALK_SPEED };
+    controller.velocity.x = direction.x * speed;
+    controller.velocity.z = direction.z * speed;
+    controller.velocity.y = (controller.velocity.y - GRAVITY * time.delta_seconds()).max(-TERMINAL_VELOCITY);
+    if controller.on_ground && inputs.pressed(KeyCode::Space) {
+        controller.velocity.y = JUMP_VELOCITY;
+    }
+
+    let step_height = if controller.auto_step { STEP_HEIGHT } else { 0. };
+    let result = world.sweep_aabb(player_aabb(transform.translation), controller.velocity * time.delta_seconds(), step_height);
+    transform.translation = result.aabb.center();
+    if result.blocked[1] {
+        controller.velocity.y = 0.;
+    }
+    controller.on_ground = result.on_ground;
+}
+
+fn fly(time: &Time, inputs: &Input<KeyCode>, movement: Vec3, transform: &mut Transform) {
     let mut speed = 5.;
     if inputs.pressed(KeyCode::LControl) { speed = 100.; }
     if inputs.pressed(KeyCode::Space) { transform.translation.y += time.delta_seconds() * speed }
@@ -83,7 +198,70 @@ pub fn update_controller(
     new_translation = new_translation.normalize() * time.delta_seconds() * speed; // TODO: SPEED HARD CODED FIX LATER
     info!("{} {} {}", new_translation.x, transform.translation.y, new_translation.z);
     transform.translation += new_translation;
+}
 
+/* Breaks the targeted voxel on left click and places the selected voxel against it on right click */
+pub fn update_block_interaction(
+    inputs: Res<Input<KeyCode>>,
+    mouse_buttons: Res<Input<MouseButton>>,
+    mut mouse_wheel: EventReader<MouseWheel>,
+    mut world: ResMut<World>,
+    mut player_query: Query<(&Transform, &mut PlayerController)>,
+    camera_query: Query<&GlobalTransform, With<PlayerCamera>>,
+    mut highlight_query: Query<(&mut Transform, &mut Visibility), HighlightFilter>
+) {
+    let (player, mut controller): (&Transform, Mut<PlayerController>) = player_query.single_mut();
+    // The number keys select blocks by id, skipping air
+    const BLOCK_KEYS: [KeyCode; 9] = [
+        KeyCode::Key1, KeyCode::Key2, KeyCode::Key3, KeyCode::Key4, KeyCode::Key5,
+        KeyCode::Key6, KeyCode::Key7, KeyCode::Key8, KeyCode::Key9
+    ];
+    for (i, key) in BLOCK_KEYS.iter().enumerate() {
+        let block = BlockId(i as u16 + 1);
+        if inputs.just_pressed(*key) && world.blocks.contains(block) {
+            controller.selected_block = block;
+        }
+    }
+    // Scrolling cycles through every block that can be placed
+    let scroll: f32 = mouse_wheel.iter().map(|wheel| wheel.y).sum();
+    let selectable: Vec<BlockId> = if scroll != 0. { world.blocks.selectable().collect() } else { Vec::new() };
+    if !selectable.is_empty() {
+        let current = selectable.iter().position(|block| *block == controller.selected_block).unwrap_or(0);
+        let next = if scroll > 0. { current + 1 } else { current + selectable.len() - 1 };
+        controller.selected_block = selectable[next % selectable.len()];
+        info!("Selected {}", world.blocks.get(controller.selected_block).name);
+    }
+
+    // The camera sits behind the player so the ray reaches past the player, hits further than the reach from the player
+    // itself are out of range
+    let camera: &GlobalTransform = camera_query.single();
+    let max_distance = camera.translation.distance(player.translation) + controller.reach;
+    let hit = world.raycast(camera.translation, camera.forward(), max_distance)
+        .filter(|hit| (camera.translation + camera.forward() * hit.distance).distance(player.translation) <= controller.reach);
+
+    let (mut highlight, mut visibility) = highlight_query.single_mut();
+    visibility.is_visible = hit.is_some();
+    let hit = match hit {
+        Some(hit) => hit,
+        None => return
+    };
+    highlight.translation = vec3(hit.position.0 as f32, hit.position.1 as f32, hit.position.2 as f32);
+
+    if mouse_buttons.just_pressed(MouseButton::Left) {
+        world.set_voxel(hit.position, BlockId::AIR);
+    } else if mouse_buttons.just_pressed(MouseButton::Right) && hit.normal != (0, 0, 0) {
+        let placed = Aabb {
+            min: vec3(hit.previous.0 as f32, hit.previous.1 as f32, hit.previous.2 as f32),
+            max: vec3(hit.previous.0 as f32 + 1., hit.previous.1 as f32 + 1., hit.previous.2 as f32 + 1.)
+        };
+        if controller.mode == MovementMode::Walking && placed.intersects(&player_aabb(player.translation)) {
+            return;
+        }
+        // Blocks with a facing, like stairs, face the way the player looks
+        let forward = camera.forward();
+        let block = world.blocks.facing_variant(controller.selected_block, Facing::from_direction(forward.x, forward.z));
+        world.set_voxel(hit.previous, block);
+    }
 }
 
 pub fn debug_player(query: Query<&Transform, With<PlayerController>>) {
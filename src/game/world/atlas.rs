@@ -0,0 +1,185 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use bevy::prelude::*;
+use bevy::render::render_resource::{Extent3d, FilterMode, TextureDimension, TextureFormat};
+use bevy::render::texture::{CompressedImageFormats, ImageType};
+use bevy::utils::HashMap;
+use crate::game::world::block::{BlockFace, BlockId, BlockRegistry};
+
+pub const TILE_SIZE: u32 = 16;
+const TILE_BYTES: usize = (TILE_SIZE * TILE_SIZE * 4) as usize;
+
+/* Rectangle of a tile in atlas uv space as (min u, min v, width, height) */
+pub type AtlasTile = [f32; 4];
+
+const FULL_TILE: AtlasTile = [0., 0., 1., 1.];
+
+/* Packs equally sized tiles into a square grid, each texture is only added once however many faces use it */
+#[derive(Default)]
+struct AtlasBuilder {
+    directory: PathBuf,
+    tiles: Vec<Vec<u8>>,
+    names: HashMap<String, usize>
+}
+
+impl AtlasBuilder {
+    fn new(directory: impl AsRef<Path>) -> Self {
+        Self {
+            directory: directory.as_ref().to_path_buf(),
+            ..default()
+        }
+    }
+
+    fn add_texture(&mut self, name: &str) -> Option<usize> {
+        if let Some(tile) = self.names.get(name) {
+            return Some(*tile);
+        }
+        let tile = match self.load_texture(name) {
+            Ok(tile) => tile,
+            Err(error) => {
+                error!("Failed to load block texture {}: {}", name, error);
+                return None;
+            }
+        };
+        self.tiles.push(tile);
+        self.names.insert(name.to_string(), self.tiles.len() - 1);
+        Some(self.tiles.len() - 1)
+    }
+
+    fn load_texture(&self, name: &str) -> Result<Vec<u8>, String> {
+        let path = self.directory.join(format!("{}.png", name));
+        let bytes = fs::read(&path).map_err(|error| format!("{}: {}", path.display(), error))?;
+        let image = Image::from_buffer(&bytes, ImageType::Extension("png"), CompressedImageFormats::NONE, true)
+            .map_err(|error| error.to_string())?;
+        let size = image.texture_descriptor.size;
+        if size.width != TILE_SIZE || size.height != TILE_SIZE {
+            return Err(format!("expected {}x{} pixels but found {}x{}", TILE_SIZE, TILE_SIZE, size.width, size.height));
+        }
+        let image = image.convert(TextureFormat::Rgba8UnormSrgb).ok_or("unsupported texture format")?;
+        Ok(image.data)
+    }
+
+    /* Solid tile used for blocks without a texture */
+    fn add_color(&mut self, color: [f32; 4]) -> usize {
+        let pixel = color.map(|channel| (channel.clamp(0., 1.) * 255.).round() as u8);
+        self.tiles.push(pixel.repeat(TILE_BYTES / 4));
+        self.tiles.len() - 1
+    }
+
+    fn columns(&self) -> u32 {
+        (self.tiles.len().max(1) as f32).sqrt().ceil() as u32
+    }
+
+    fn tile_uv(&self, tile: usize) -> AtlasTile {
+        let columns = self.columns() as f32;
+        let size = 1. / columns;
+        [(tile as f32 % columns) * size, (tile as f32 / columns).floor() * size, size, size]
+    }
+
+    fn finish(&self) -> Image {
+        let columns = self.columns();
+        let length = columns * TILE_SIZE;
+        let row_bytes = (TILE_SIZE * 4) as usize;
+        let mut data = vec![0; (length * length * 4) as usize];
+        for (tile, pixels) in self.tiles.iter().enumerate() {
+            let origin_x = tile as u32 % columns * TILE_SIZE;
+            let origin_y = tile as u32 / columns * TILE_SIZE;
+            for (row, row_pixels) in pixels.chunks(row_bytes).enumerate() {
+                let start = (((origin_y + row as u32) * length + origin_x) * 4) as usize;
+                data[start..start + row_bytes].copy_from_slice(row_pixels);
+            }
+        }
+
+        let mut image = Image::new(
+            Extent3d { width: length, height: length, depth_or_array_layers: 1 },
+            TextureDimension::D2,
+            data,
+            TextureFormat::Rgba8UnormSrgb
+        );
+        // Pixel art textures stay sharp up close
+        image.sampler_descriptor.mag_filter = FilterMode::Nearest;
+        image.sampler_descriptor.min_filter = FilterMode::Nearest;
+        image
+    }
+}
+
+/* Atlas tile of every face of every block, indexed by block id */
+#[derive(Default)]
+pub struct BlockAtlas {
+    faces: Vec<[AtlasTile; 3]>
+}
+
+impl BlockAtlas {
+    /* Packs the textures named by the registry from the directory, blocks or faces without a loadable texture get a tile in the block colour */
+    pub fn build(directory: impl AsRef<Path>, blocks: &BlockRegistry) -> (Self, Image) {
+        let mut builder = AtlasBuilder::new(directory);
+        let mut face_tiles = Vec::new();
+        for block in blocks.iter() {
+            let mut color_tile = None;
+            let tiles = BlockFace::ALL.map(|face| {
+                match block.textures.get(face).and_then(|name| builder.add_texture(name)) {
+                    Some(tile) => tile,
+                    None => *color_tile.get_or_insert_with(|| builder.add_color(block.color))
+                }
+            });
+            face_tiles.push(tiles);
+        }
+
+        let faces = face_tiles.iter().map(|tiles| tiles.map(|tile| builder.tile_uv(tile))).collect();
+        (Self { faces }, builder.finish())
+    }
+
+    /* Blocks missing from the atlas use the whole image */
+    pub fn tile(&self, block: BlockId, face: BlockFace) -> AtlasTile {
+        match self.faces.get(block.0 as usize) {
+            Some(tiles) => tiles[face as usize],
+            None => FULL_TILE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::world::block::test_blocks::{self, MISSING_TEXTURE};
+
+    #[test]
+    fn packs_bundled_textures() {
+        let blocks = BlockRegistry::load("assets/blocks.ron").unwrap();
+        let (atlas, image) = BlockAtlas::build("assets/textures/blocks", &blocks);
+
+        // Faces naming the same texture share a tile and different textures never do
+        let mut texture_tiles: HashMap<&str, AtlasTile> = HashMap::default();
+        let mut tiles: Vec<AtlasTile> = Vec::new();
+        for block in blocks.iter() {
+            for face in BlockFace::ALL {
+                let tile = atlas.tile(block.id, face);
+                if let Some(name) = block.textures.get(face) {
+                    assert_eq!(*texture_tiles.entry(name).or_insert(tile), tile, "{} has more than one tile", name);
+                }
+                if !tiles.contains(&tile) {
+                    tiles.push(tile);
+                }
+            }
+        }
+        let distinct_textures: Vec<&AtlasTile> = texture_tiles.values().collect();
+        assert!(distinct_textures.iter().enumerate().all(|(i, tile)| !distinct_textures[..i].contains(tile)));
+
+        // The grid is square and has room for every tile
+        let size = image.texture_descriptor.size;
+        assert_eq!(size.width, size.height);
+        let columns = size.width / TILE_SIZE;
+        assert!((columns * columns) as usize >= tiles.len());
+        assert_eq!(atlas.tile(BlockId(u16::MAX), BlockFace::Top), FULL_TILE);
+    }
+
+    #[test]
+    fn missing_textures_fall_back_to_block_colour() {
+        let (atlas, image) = BlockAtlas::build("assets/textures/blocks", &test_blocks::registry());
+
+        let tile = atlas.tile(MISSING_TEXTURE, BlockFace::Top);
+        let length = image.texture_descriptor.size.width as f32;
+        let start = (((tile[1] * length) as usize * length as usize) + (tile[0] * length) as usize) * 4;
+        assert_eq!(&image.data[start..start + 4], &[255, 0, 0, 255]);
+    }
+}
@@ -0,0 +1,276 @@
+use std::fs;
+use std::path::Path;
+use bevy::utils::HashMap;
+use serde::Deserialize;
+use crate::game::world::light::{Light, MAX_LIGHT};
+use crate::game::world::model::{BlockModel, Facing};
+
+/* Compact numeric id of a block, id 0 is always air */
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct BlockId(pub u16);
+
+impl BlockId {
+    pub const AIR: BlockId = BlockId(0);
+
+    pub fn is_air(&self) -> bool {
+        *self == BlockId::AIR
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockFace {
+    Top,
+    Bottom,
+    Side
+}
+
+impl BlockFace {
+    pub const ALL: [BlockFace; 3] = [BlockFace::Top, BlockFace::Bottom, BlockFace::Side];
+}
+
+/* Texture names per face, `all` is used for any face that isn't given */
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct BlockTextures {
+    pub all: Option<String>,
+    pub top: Option<String>,
+    pub bottom: Option<String>,
+    pub side: Option<String>
+}
+
+impl BlockTextures {
+    pub fn get(&self, face: BlockFace) -> Option<&str> {
+        let texture = match face {
+            BlockFace::Top => &self.top,
+            BlockFace::Bottom => &self.bottom,
+            BlockFace::Side => &self.side
+        };
+        texture.as_ref().or(self.all.as_ref()).map(|texture| texture.as_str())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BlockDefinition {
+    pub id: BlockId,
+    pub name: String,
+    #[serde(default = "default_solid")]
+    pub solid: bool,
+    /* Transparent blocks don't hide the faces of the blocks next to them */
+    #[serde(default)]
+    pub transparent: bool,
+    /* Translucent blocks are partly see-through and blended with what is behind them, they count as transparent and also dim
+       the light passing through them */
+    #[serde(default)]
+    pub translucent: bool,
+    #[serde(default)]
+    pub model: BlockModel,
+    #[serde(default)]
+    pub textures: BlockTextures,
+    #[serde(default = "default_color")]
+    pub color: [f32; 4],
+    /* Block light given off from 0 to 15, tinted by the emission colour */
+    #[serde(default)]
+    pub emission: u8,
+    #[serde(default = "default_emission_color")]
+    pub emission_color: [f32; 3]
+}
+
+fn default_solid() -> bool {
+    true
+}
+
+fn default_color() -> [f32; 4] {
+    [1., 1., 1., 1.]
+}
+
+fn default_emission_color() -> [f32; 3] {
+    [1., 1., 1.]
+}
+
+/* Every block definition indexed by id, loaded from a RON list of definitions */
+#[derive(Default)]
+pub struct BlockRegistry {
+    blocks: Vec<BlockDefinition>,
+    names: HashMap<String, BlockId>
+}
+
+impl BlockRegistry {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path).map_err(|error| format!("Failed to read {}: {}", path.display(), error))?;
+        Self::from_ron(&source)
+    }
+
+    pub fn from_ron(source: &str) -> Result<Self, String> {
+        let definitions: Vec<BlockDefinition> = ron::from_str(source).map_err(|error| error.to_string())?;
+
+        let mut blocks: Vec<Option<BlockDefinition>> = Vec::new();
+        let mut names = HashMap::default();
+        for definition in definitions {
+            let index = definition.id.0 as usize;
+            if blocks.len() <= index {
+                blocks.resize(index + 1, None);
+            }
+            if blocks[index].is_some() {
+                return Err(format!("Block id {} is defined twice", index));
+            }
+            if definition.emission > MAX_LIGHT {
+                return Err(format!("Block {} emits more than the maximum light level {}", definition.name, MAX_LIGHT));
+            }
+            if names.insert(definition.name.clone(), definition.id).is_some() {
+                return Err(format!("Block name {} is defined twice", definition.name));
+            }
+            blocks[index] = Some(definition);
+        }
+
+        let blocks: Vec<BlockDefinition> = blocks.into_iter().enumerate()
+            .map(|(id, definition)| definition.ok_or(format!("Block id {} is missing", id)))
+            .collect::<Result<_, _>>()?;
+        match blocks.first() {
+            Some(air) if !air.solid && air.transparent => {}
+            _ => return Err("Block id 0 must be a non solid transparent air block".to_string())
+        }
+        Ok(Self { blocks, names })
+    }
+
+    /* Unknown ids, such as ones from a save made with more blocks, fall back to air */
+    pub fn get(&self, id: BlockId) -> &BlockDefinition {
+        self.blocks.get(id.0 as usize).unwrap_or(&self.blocks[0])
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<BlockId> {
+        self.names.get(name).copied()
+    }
+
+    pub fn contains(&self, id: BlockId) -> bool {
+        (id.0 as usize) < self.blocks.len()
+    }
+
+    pub fn is_solid(&self, id: BlockId) -> bool {
+        self.get(id).solid
+    }
+
+    pub fn is_transparent(&self, id: BlockId) -> bool {
+        let block = self.get(id);
+        block.transparent || block.translucent
+    }
+
+    /* Blocks that face a direction are defined once per facing, named `<name>_north`, `<name>_south` and so on. Returns the
+       variant of the block facing `facing`, or the block itself if it has no facing variants */
+    pub fn facing_variant(&self, id: BlockId, facing: Facing) -> BlockId {
+        let name = &self.get(id).name;
+        Facing::ALL.iter()
+            .find_map(|variant| name.strip_suffix(variant.name())?.strip_suffix('_'))
+            .and_then(|base| self.by_name(&format!("{}_{}", base, facing.name())))
+            .unwrap_or(id)
+    }
+
+    /* Blocks the player can pick to place, leaving out air and listing blocks that face a direction once by their north variant */
+    pub fn selectable(&self) -> impl Iterator<Item = BlockId> + '_ {
+        self.blocks.iter()
+            .map(|definition| definition.id)
+            .filter(|id| !id.is_air() && self.facing_variant(*id, Facing::North) == *id)
+    }
+
+    /* Full cubes that can't be seen through hide the faces against them and block light, shaped blocks never do */
+    pub fn is_opaque(&self, id: BlockId) -> bool {
+        !self.is_transparent(id) && self.model(id) == BlockModel::Cube
+    }
+
+    pub fn is_translucent(&self, id: BlockId) -> bool {
+        self.get(id).translucent
+    }
+
+    pub fn model(&self, id: BlockId) -> BlockModel {
+        self.get(id).model
+    }
+
+    /* Block light the block gives off, dark for blocks that don't emit */
+    pub fn emission(&self, id: BlockId) -> Light {
+        let block = self.get(id);
+        Light::emitted(block.emission, block.emission_color)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &BlockDefinition> {
+        self.blocks.iter()
+    }
+}
+
+/* One block of each kind the engine treats differently, shared by the tests of every module */
+#[cfg(test)]
+pub(crate) mod test_blocks {
+    use super::*;
+
+    pub const STONE: BlockId = BlockId(1);
+    pub const GRASS: BlockId = BlockId(2);
+    pub const DIRT: BlockId = BlockId(3);
+    pub const WATER: BlockId = BlockId(4);
+    pub const GLASS: BlockId = BlockId(5);
+    pub const SLAB: BlockId = BlockId(6);
+    pub const PLANT: BlockId = BlockId(7);
+    pub const LAMP: BlockId = BlockId(8);
+    pub const FENCE: BlockId = BlockId(9);
+    /* Red block whose texture doesn't exist */
+    pub const MISSING_TEXTURE: BlockId = BlockId(10);
+
+    pub fn registry() -> BlockRegistry {
+        BlockRegistry::from_ron(r#"[
+            (id: 0, name: "air", solid: false, transparent: true),
+            (id: 1, name: "stone"),
+            (id: 2, name: "grass"),
+            (id: 3, name: "dirt"),
+            (id: 4, name: "water", solid: false, translucent: true),
+            (id: 5, name: "glass", transparent: true),
+            (id: 6, name: "slab", model: Slab),
+            (id: 7, name: "plant", solid: false, transparent: true, model: Cross),
+            (id: 8, name: "lamp", emission: 12, emission_color: (1.0, 0.5, 0.0)),
+            (id: 9, name: "fence", model: Fence),
+            (id: 10, name: "missing_texture", textures: (all: Some("does_not_exist")), color: (1.0, 0.0, 0.0, 1.0)),
+        ]"#).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_bundled_blocks() {
+        let blocks = BlockRegistry::load("assets/blocks.ron").unwrap();
+        let grass = blocks.by_name("grass").unwrap();
+        assert!(blocks.is_solid(grass) && !blocks.is_transparent(grass));
+        assert!(blocks.is_transparent(BlockId::AIR));
+        assert!(!blocks.is_solid(BlockId(u16::MAX)));
+        assert_eq!(blocks.emission(grass), Light::DARK);
+        let water = blocks.by_name("water").unwrap();
+        assert!(blocks.is_transparent(water) && blocks.is_translucent(water));
+        assert!(!blocks.is_translucent(blocks.by_name("glass").unwrap()));
+        assert!(blocks.is_opaque(grass) && !blocks.is_opaque(water));
+        assert!(!blocks.is_transparent(blocks.by_name("cobblestone_slab").unwrap()) && !blocks.is_opaque(blocks.by_name("cobblestone_slab").unwrap()));
+        assert_eq!(blocks.emission(blocks.by_name("lava").unwrap()).block(), [15, 8, 2]);
+    }
+
+    #[test]
+    fn finds_facing_variants_by_name() {
+        let blocks = BlockRegistry::load("assets/blocks.ron").unwrap();
+        let north = blocks.by_name("cobblestone_stairs_north").unwrap();
+        let east = blocks.facing_variant(north, Facing::East);
+        assert_eq!(blocks.model(east), BlockModel::Stairs(Facing::East));
+        assert_eq!(blocks.facing_variant(east, Facing::North), north);
+        let stone = blocks.by_name("stone").unwrap();
+        assert_eq!(blocks.facing_variant(stone, Facing::West), stone);
+
+        let selectable: Vec<BlockId> = blocks.selectable().collect();
+        assert!(selectable.contains(&north) && selectable.contains(&stone));
+        assert!(!selectable.contains(&east) && !selectable.contains(&BlockId::AIR));
+    }
+
+    #[test]
+    fn rejects_invalid_definitions() {
+        assert!(BlockRegistry::from_ron(r#"[(id: 0, name: "air", solid: false, transparent: true), (id: 2, name: "stone")]"#).is_err());
+        assert!(BlockRegistry::from_ron(r#"[(id: 0, name: "air", solid: false, transparent: true), (id: 1, name: "air")]"#).is_err());
+        assert!(BlockRegistry::from_ron(r#"[(id: 0, name: "stone")]"#).is_err());
+        assert!(BlockRegistry::from_ron(r#"[(id: 0, name: "air", solid: false, transparent: true), (id: 1, name: "sun", emission: 16)]"#).is_err());
+    }
+}
@@ -1,18 +1,45 @@
-use std::cmp::max;
+use std::ops::Range;
 use bevy::prelude::*;
-use bevy::render::mesh::{Indices, MeshVertexAttribute, PrimitiveTopology, VertexAttributeValues};
-use crate::game::world::generator::TerrainGenerator;
+use bevy::render::mesh::{Indices, PrimitiveTopology};
+use crate::game::world::atlas::BlockAtlas;
+use crate::game::world::block::{BlockFace, BlockId, BlockRegistry};
+use crate::game::world::generator::{Decoration, TerrainBlocks, TerrainGenerator};
+use crate::game::world::light::{Light, LightStorage, MAX_LIGHT};
+use crate::game::world::material::ATTRIBUTE_ATLAS_TILE;
+use crate::game::world::model::{BlockModel, ModelBox};
+use crate::game::world::palette::PalettedStorage;
 use crate::game::world::voxel::*;
 
 pub const CHUNK_LENGTH: usize = 16;
 pub const CHUNK_AREA: usize = CHUNK_LENGTH * CHUNK_LENGTH;
 pub const CHUNK_VOLUME: usize = CHUNK_LENGTH * CHUNK_LENGTH * CHUNK_LENGTH;
+/* Bumped whenever the layout written by Chunk::serialize changes */
+const CHUNK_FORMAT_VERSION: u8 = 4;
+/* Oldest version still read, it lacks the overflow trailers */
+const OLDEST_CHUNK_FORMAT_VERSION: u8 = 2;
+const OVERFLOW_ENTRY_BYTES: usize = 14;
+const RECEIVED_ENTRY_BYTES: usize = 8;
 
+/* How section meshes are built, naive emits a quad per exposed voxel face while greedy merges coplanar faces of the same block */
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum MeshingMode {
+    Naive,
+    #[default]
+    Greedy,
+}
+
+/* A column of sections, the sections vector holds a contiguous run of signed section indices starting at `min_section` and grows in either direction as voxels are set */
 pub struct Chunk {
     position: (i32, i32),
     sections: Vec<ChunkSection>,
+    min_section: i32,
     terrain_generated: bool,
-    mesh_calculated: bool
+    /* Set once the chunk differs from what the generator would produce, only edited chunks are saved */
+    edited: bool,
+    /* Decoration blocks at world positions in neighboring chunks, the world hands them over whenever the neighbor is loaded */
+    overflow: Vec<((i32, i32, i32), BlockId)>,
+    /* Neighboring chunks whose overflow was already handed to this chunk, it isn't taken again after either chunk reloads */
+    received_overflow: Vec<(i32, i32)>
 }
 
 impl Chunk {
@@ -20,145 +47,401 @@ impl Chunk {
         Self {
             position,
             sections: Vec::new(),
+            min_section: 0,
             terrain_generated: false,
-            mesh_calculated: false
+            edited: false,
+            overflow: Vec::new(),
+            received_overflow: Vec::new()
         }
     }
 
-    pub fn generate_voxels(&mut self, generator: &TerrainGenerator) {
+    pub fn generate_voxels(&mut self, generator: &TerrainGenerator, blocks: &TerrainBlocks) {
         for x in 0..CHUNK_LENGTH {
             for z in 0..CHUNK_LENGTH {
-                let height = generator.get_height(x as i32 + self.position.0 * CHUNK_LENGTH as i32, z as i32 + self.position.1 * CHUNK_LENGTH as i32) as u32;
-                self.set_voxel((x as u32, height, z as u32), VoxelType::Grass);
-                for y in (max(height-3, 0))..height {
-                    self.set_voxel((x as u32, y, z as u32), VoxelType::Dirt)
+                let column = generator.generate_column(x as i32 + self.position.0 * CHUNK_LENGTH as i32, z as i32 + self.position.1 * CHUNK_LENGTH as i32, blocks);
+                for (i, block) in column.blocks.iter().enumerate() {
+                    if !block.is_air() {
+                        self.set_voxel((x as u32, column.bottom + i as i32, z as u32), *block);
+                    }
                 }
             }
         }
+        for (position, ore) in generator.ore_veins(self.position, blocks) {
+            if self.get_voxel(position).block == blocks.stone {
+                self.set_voxel(position, ore);
+            }
+        }
+        for (column, decoration) in generator.decorations(self.position) {
+            self.place_decoration(column, decoration, blocks);
+        }
+        for section in &mut self.sections {
+            section.voxels.optimize();
+        }
         self.terrain_generated = true;
     }
 
-    pub fn is_generated(&self) -> bool {
-        self.terrain_generated
+    pub fn is_edited(&self) -> bool {
+        self.edited
+    }
+
+    pub fn mark_edited(&mut self) {
+        self.edited = true;
+    }
+
+    /* Grows the decoration from the air above the column's surface, blocks outside the chunk are kept for the neighbor */
+    fn place_decoration(&mut self, column: (u32, u32), decoration: Decoration, blocks: &TerrainBlocks) {
+        let surface = match self.surface_height(column) {
+            Some(surface) => surface,
+            None => return
+        };
+        if !decoration.can_grow_on(self.get_voxel((column.0, surface, column.1)).block, blocks) {
+            return;
+        }
+        let origin = (self.position.0 * CHUNK_LENGTH as i32 + column.0 as i32, surface + 1, self.position.1 * CHUNK_LENGTH as i32 + column.1 as i32);
+        for (offset, block) in decoration.blocks(blocks) {
+            let position = (origin.0 + offset.0, origin.1 + offset.1, origin.2 + offset.2);
+            match self.local_position(position) {
+                Some(local) => { self.fill_air(local, block); }
+                None => self.overflow.push((position, block))
+            }
+        }
+    }
+
+    fn surface_height(&self, column: (u32, u32)) -> Option<i32> {
+        let range = self.section_range();
+        (range.start * CHUNK_LENGTH as i32..range.end * CHUNK_LENGTH as i32)
+            .rev()
+            .find(|y| !self.get_voxel((column.0, *y, column.1)).block.is_air())
+    }
+
+    /* Sets the voxel only if it is air so generated blocks never overwrite terrain or each other, returns whether it was set */
+    pub fn fill_air(&mut self, position: (u32, i32, u32), block: BlockId) -> bool {
+        if !self.get_voxel(position).block.is_air() {
+            return false;
+        }
+        self.set_voxel(position, block);
+        true
+    }
+
+    /* Overflowing decoration blocks that belong to the chunk at `position` */
+    pub fn overflow_into(&self, position: (i32, i32)) -> impl Iterator<Item = ((i32, i32, i32), BlockId)> + '_ {
+        let length = CHUNK_LENGTH as i32;
+        self.overflow.iter()
+            .filter(move |((x, _, z), _)| (x.div_euclid(length), z.div_euclid(length)) == position)
+            .copied()
+    }
+
+    pub fn has_received_overflow(&self, source: (i32, i32)) -> bool {
+        self.received_overflow.contains(&source)
+    }
+
+    pub fn record_received_overflow(&mut self, source: (i32, i32)) {
+        if !self.has_received_overflow(source) {
+            self.received_overflow.push(source);
+        }
+    }
+
+    fn local_position(&self, position: (i32, i32, i32)) -> Option<(u32, i32, u32)> {
+        let length = CHUNK_LENGTH as i32;
+        if (position.0.div_euclid(length), position.2.div_euclid(length)) != self.position {
+            return None;
+        }
+        Some((position.0.rem_euclid(length) as u32, position.1, position.2.rem_euclid(length) as u32))
+    }
+
+    /* Writes the format version, lowest section index and section count followed by every block id of each section, then the
+       decoration blocks that overflowed into neighboring chunks and the neighbors whose overflow was received */
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(7 + self.sections.len() * CHUNK_VOLUME * 2);
+        bytes.push(CHUNK_FORMAT_VERSION);
+        bytes.extend(self.min_section.to_le_bytes());
+        bytes.extend((self.sections.len() as u16).to_le_bytes());
+        for section in &self.sections {
+            for i in 0..CHUNK_VOLUME {
+                bytes.extend(section.voxels.get(i).block.0.to_le_bytes());
+            }
+        }
+        bytes.extend((self.overflow.len() as u32).to_le_bytes());
+        for ((x, y, z), block) in &self.overflow {
+            bytes.extend(x.to_le_bytes());
+            bytes.extend(y.to_le_bytes());
+            bytes.extend(z.to_le_bytes());
+            bytes.extend(block.0.to_le_bytes());
+        }
+        bytes.extend((self.received_overflow.len() as u32).to_le_bytes());
+        for (x, z) in &self.received_overflow {
+            bytes.extend(x.to_le_bytes());
+            bytes.extend(z.to_le_bytes());
+        }
+        bytes
+    }
+
+    /* Reads chunks written by this or an older supported version, chunks saved before decorations overflowed have none */
+    pub fn deserialize(position: (i32, i32), bytes: &[u8]) -> Option<Self> {
+        let version = *bytes.first()?;
+        if !(OLDEST_CHUNK_FORMAT_VERSION..=CHUNK_FORMAT_VERSION).contains(&version) {
+            return None;
+        }
+        let min_section = i32::from_le_bytes(bytes.get(1..5)?.try_into().ok()?);
+        let section_count = u16::from_le_bytes([*bytes.get(5)?, *bytes.get(6)?]) as usize;
+        let voxels_end = 7 + section_count * CHUNK_VOLUME * 2;
+        let voxels = bytes.get(7..voxels_end)?;
+        // Each trailer is a count followed by its entries, older versions end before the trailers they didn't have yet
+        let mut end = voxels_end;
+        let mut trailer = |present: bool, entry_bytes: usize| -> Option<&[u8]> {
+            if !present {
+                return Some(&[]);
+            }
+            let count = u32::from_le_bytes(bytes.get(end..end + 4)?.try_into().ok()?) as usize;
+            let entries = bytes.get(end + 4..end + 4 + count * entry_bytes)?;
+            end += 4 + count * entry_bytes;
+            Some(entries)
+        };
+        let overflow = trailer(version >= 3, OVERFLOW_ENTRY_BYTES)?;
+        let received = trailer(version >= 4, RECEIVED_ENTRY_BYTES)?;
+        if bytes.len() != end {
+            return None;
+        }
+
+        let mut chunk = Chunk::new(position);
+        chunk.min_section = min_section;
+        for (i, section_voxels) in voxels.chunks(CHUNK_VOLUME * 2).enumerate() {
+            let mut section = ChunkSection::new((position.0, min_section + i as i32, position.1));
+            for (i, id) in section_voxels.chunks(2).enumerate() {
+                section.voxels.set(i, Voxel::new(BlockId(u16::from_le_bytes([id[0], id[1]]))));
+            }
+            section.voxels.optimize();
+            chunk.sections.push(section);
+        }
+        for entry in overflow.chunks(OVERFLOW_ENTRY_BYTES) {
+            let int = |start: usize| i32::from_le_bytes(entry[start..start + 4].try_into().unwrap());
+            chunk.overflow.push(((int(0), int(4), int(8)), BlockId(u16::from_le_bytes([entry[12], entry[13]]))));
+        }
+        for entry in received.chunks(RECEIVED_ENTRY_BYTES) {
+            let int = |start: usize| i32::from_le_bytes(entry[start..start + 4].try_into().unwrap());
+            chunk.received_overflow.push((int(0), int(4)));
+        }
+        chunk.terrain_generated = true;
+        chunk.edited = true;
+        Some(chunk)
     }
 
-    pub fn is_mesh_calculated(&self) -> bool {
-        self.mesh_calculated
+    pub fn is_generated(&self) -> bool {
+        self.terrain_generated
     }
 
     pub fn get_position(&self) -> (i32, i32) {
         self.position
     }
 
-    pub fn generate_bevy_meshes(&self) -> Vec<Mesh> {
+    pub fn create_section_bevy_mesh(&self, y: i32, layer: MeshLayer) -> Option<Mesh> {
         if !self.terrain_generated { error!("Chunk not loaded yet") }
-        self.sections.iter().map(|section| -> Mesh {
-            section.create_bevy_mesh()
-        }).collect()
+        Some(self.get_section(y)?.create_bevy_mesh(layer))
     }
 
-    pub fn generate_chunk_meshes(&mut self) {
+    pub fn has_section_faces(&self, y: i32, layer: MeshLayer) -> bool {
+        matches!(self.get_section(y), Some(section) if !section.meshes.get(layer).indices.is_empty())
+    }
+
+    pub fn set_section_meshes(&mut self, y: i32, meshes: SectionMeshes) {
         if !self.terrain_generated { error!("Chunk not loaded yet") }
-        for mut section in &mut self.sections {
-            if !section.mesh_generated {
-                section.generate_chunk_mesh();
+        if let Some(section) = self.get_section_mut(y) {
+            section.meshes = meshes;
+            section.mesh_generated = true;
+        }
+    }
+
+    pub fn get_section(&self, y: i32) -> Option<&ChunkSection> {
+        let index = usize::try_from(y - self.min_section).ok()?;
+        self.sections.get(index)
+    }
+
+    fn get_section_mut(&mut self, y: i32) -> Option<&mut ChunkSection> {
+        let index = usize::try_from(y - self.min_section).ok()?;
+        self.sections.get_mut(index)
+    }
+
+    /* Indices of the sections stored in this column, sections outside it are empty */
+    pub fn section_range(&self) -> Range<i32> {
+        self.min_section..self.min_section + self.sections.len() as i32
+    }
+
+    /* The y of the position is a world height, x and z are local to the chunk */
+    pub fn set_voxel(&mut self, position: (u32, i32, u32), block: BlockId) {
+        if !Self::in_bounds(position) { error!("Voxel position out of bounds"); }
+        let section_y = position.1.div_euclid(CHUNK_LENGTH as i32);
+        self.add_y_sections(section_y);
+        let section = self.get_section_mut(section_y).unwrap();
+        section.set_voxel((position.0, position.1.rem_euclid(CHUNK_LENGTH as i32) as u32, position.2), block);
+    }
+
+    /* Light above the column is open sky and below it is dark */
+    pub fn get_light(&self, position: (u32, i32, u32)) -> Light {
+        let section_y = position.1.div_euclid(CHUNK_LENGTH as i32);
+        match self.get_section(section_y) {
+            Some(section) => section.light.get(ChunkSection::get_index(position.0, position.1.rem_euclid(CHUNK_LENGTH as i32) as u32, position.2)),
+            None if section_y >= self.min_section => Light::SKY,
+            None => Light::DARK
+        }
+    }
+
+    /* Light outside the column's sections is implied by `get_light` and isn't stored, setting it does nothing */
+    pub fn set_light(&mut self, position: (u32, i32, u32), light: Light) {
+        let section_y = position.1.div_euclid(CHUNK_LENGTH as i32);
+        let section = match self.get_section_mut(section_y) {
+            Some(section) => section,
+            None => return
+        };
+        section.light.set(ChunkSection::get_index(position.0, position.1.rem_euclid(CHUNK_LENGTH as i32) as u32, position.2), light);
+    }
+
+    /* Every light emitting voxel along with the light it gives off */
+    pub fn emitters(&self, blocks: &BlockRegistry) -> Vec<((u32, i32, u32), Light)> {
+        let mut emitters = Vec::new();
+        for section in &self.sections {
+            // Only sections with an emitter in their palette are worth scanning
+            if !section.voxels.any(|voxel| blocks.emission(voxel.block) != Light::DARK) {
+                continue;
+            }
+            for i in 0..CHUNK_VOLUME {
+                let emission = blocks.emission(section.voxels.get(i).block);
+                if emission != Light::DARK {
+                    let y = section.position.1 * CHUNK_LENGTH as i32 + (i / CHUNK_AREA) as i32;
+                    emitters.push((((i % CHUNK_LENGTH) as u32, y, (i / CHUNK_LENGTH % CHUNK_LENGTH) as u32), emission));
+                }
             }
         }
+        emitters
+    }
 
-        self.mesh_calculated = true;
+    pub fn clear_light(&mut self) {
+        for section in &mut self.sections {
+            section.light = LightStorage::new(Light::DARK);
+        }
+    }
+
+    pub fn optimize_light(&mut self) {
+        for section in &mut self.sections {
+            section.light.optimize();
+        }
     }
 
-    pub fn set_voxel(&mut self, position: (u32, u32, u32), material: VoxelType) {
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let mut usage = MemoryUsage::default();
+        for section in &self.sections {
+            usage += section.memory_usage();
+        }
+        usage
+    }
+
+    pub fn get_voxel(&self, position: (u32, i32, u32)) -> Voxel {
         if !Self::in_bounds(position) { error!("Voxel position out of bounds"); }
-        self.add_y_sections(position.1);
-        let section: &mut ChunkSection = &mut self.sections[position.1 as usize / CHUNK_LENGTH];
-        section.set_voxel((position.0, position.1 % CHUNK_LENGTH as u32, position.2), material);
+        match self.get_section(position.1.div_euclid(CHUNK_LENGTH as i32)) {
+            Some(section) => section.get_voxel((position.0, position.1.rem_euclid(CHUNK_LENGTH as i32) as u32, position.2)),
+            None => Voxel::air()
+        }
     }
 
-    fn add_y_sections(&mut self, y: u32) {
-        let index = y / CHUNK_LENGTH as u32;
-        while self.sections.len() <= index as usize {
-            self.sections.push(ChunkSection::new((self.position.0, self.sections.len() as i32, self.position.1)))
+    /* Extends the column up or down with empty sections until it includes section y, new sections on top are sunlit and ones
+       underneath are dark until the light engine gets to them */
+    fn add_y_sections(&mut self, y: i32) {
+        if self.sections.is_empty() {
+            self.min_section = y;
+        }
+        while y < self.min_section {
+            self.min_section -= 1;
+            let mut section = ChunkSection::new((self.position.0, self.min_section, self.position.1));
+            section.light = LightStorage::new(Light::DARK);
+            self.sections.insert(0, section);
+        }
+        while y >= self.min_section + self.sections.len() as i32 {
+            let section_y = self.min_section + self.sections.len() as i32;
+            self.sections.push(ChunkSection::new((self.position.0, section_y, self.position.1)))
         }
     }
 
-    fn in_bounds(position: (u32, u32, u32)) -> bool {
+    fn in_bounds(position: (u32, i32, u32)) -> bool {
         if position.0 >= CHUNK_LENGTH as u32 { return false; }
         if position.2 >= CHUNK_LENGTH as u32 { return false; }
         return true;
     }
 }
 
-pub struct ChunkSection {
-    voxels: Box<[Voxel; CHUNK_VOLUME]>,
-    position: (i32, i32, i32),
-    chunk_mesh: ChunkMesh,
-    mesh_generated: bool,
+#[derive(Clone, Copy, Default)]
+pub struct MemoryUsage {
+    pub sections: usize,
+    pub bytes: usize,
+    pub uncompressed_bytes: usize
 }
 
-#[derive(Default)]
-struct Directions {
-    front: (i32, i32, i32),
-    back: (i32, i32, i32),
-    left: (i32, i32, i32),
-    right: (i32, i32, i32),
-    top: (i32, i32, i32),
-    bottom: (i32, i32, i32),
+impl std::ops::AddAssign for MemoryUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.sections += other.sections;
+        self.bytes += other.bytes;
+        self.uncompressed_bytes += other.uncompressed_bytes;
+    }
 }
 
-impl Directions {
-    fn new() -> Self {
-        Self {
-            ..default()
-        }
-    }
-    fn update(&mut self, position: (i32, i32, i32)) {
-        self.front = (position.0, position.1, position.2 + 1);
-        self.back = (position.0, position.1, position.2 - 1);
-        self.left = (position.0 - 1, position.1, position.2);
-        self.right = (position.0 + 1, position.1, position.2);
-        self.top = (position.0, position.1 + 1, position.2);
-        self.bottom = (position.0, position.1 - 1, position.2);
-    }
+pub struct ChunkSection {
+    voxels: PalettedStorage,
+    light: LightStorage,
+    position: (i32, i32, i32),
+    meshes: SectionMeshes,
+    mesh_generated: bool,
 }
 
 impl ChunkSection {
     fn new(position: (i32, i32, i32)) -> Self {
         Self {
-            voxels: Box::new([Voxel::air(); CHUNK_VOLUME]),
+            voxels: PalettedStorage::new(Voxel::air()),
+            light: LightStorage::new(Light::SKY),
             position,
-            chunk_mesh: ChunkMesh::new(),
+            meshes: SectionMeshes::new(),
             mesh_generated: false
         }
     }
 
-    fn generate_chunk_mesh(&mut self) {
-        self.mesh_generated = true;
-
-        let mut builder = ChunkBuilder::new(self);
-        builder.build();
-    }
-
-    fn create_bevy_mesh(&self) -> Mesh {
+    fn create_bevy_mesh(&self, layer: MeshLayer) -> Mesh {
         if !self.mesh_generated {
             error!("Mesh data not generated yet")
         }
+        let chunk_mesh = self.meshes.get(layer);
         let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
-        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, self.chunk_mesh.vertices.clone());
-        mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, self.chunk_mesh.uvs.clone());
-        mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, self.chunk_mesh.normals.clone());
-        mesh.set_indices(Some(Indices::U32(self.chunk_mesh.indices.clone())));
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, chunk_mesh.vertices.clone());
+        mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, chunk_mesh.uvs.clone());
+        mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, chunk_mesh.normals.clone());
+        mesh.insert_attribute(ATTRIBUTE_ATLAS_TILE, chunk_mesh.tiles.clone());
+        mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, chunk_mesh.colors.clone());
+        mesh.set_indices(Some(Indices::U32(chunk_mesh.indices.clone())));
         return mesh;
     }
 
-    pub fn set_voxel(&mut self, position: (u32, u32, u32), material: VoxelType) {
+    pub fn set_voxel(&mut self, position: (u32, u32, u32), block: BlockId) {
         if !Self::in_bounds(position.0, position.1, position.2) { error!("Attempting to set voxel out of bounds") }
-        self.voxels[Self::get_index(position.0, position.1, position.2)].material = material;
+        self.voxels.set(Self::get_index(position.0, position.1, position.2), Voxel::new(block));
     }
 
-    pub fn get_voxel(&mut self, position: (u32, u32, u32)) -> &Voxel {
+    pub fn get_voxel(&self, position: (u32, u32, u32)) -> Voxel {
         if !Self::in_bounds(position.0, position.1, position.2) { error!("Attempting to get voxel out of bounds") }
-        &self.voxels[Self::get_index(position.0, position.1, position.2)]
+        self.voxels.get(Self::get_index(position.0, position.1, position.2))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        matches!(self.voxels, PalettedStorage::Uniform(voxel) if voxel.block.is_air())
+    }
+
+    /* Filled with a single block that hides whatever is behind it */
+    fn is_opaque(&self, blocks: &BlockRegistry) -> bool {
+        matches!(self.voxels, PalettedStorage::Uniform(voxel) if blocks.is_opaque(voxel.block))
+    }
+
+    /* Bytes used by the section's voxels and light, alongside what full arrays of both would take */
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            sections: 1,
+            bytes: std::mem::size_of::<PalettedStorage>() + self.voxels.heap_size() + std::mem::size_of::<LightStorage>() + self.light.heap_size(),
+            uncompressed_bytes: (std::mem::size_of::<Voxel>() + std::mem::size_of::<Light>()) * CHUNK_VOLUME
+        }
     }
 
     pub fn in_bounds(x: u32, y: u32, z: u32) -> bool {
@@ -173,10 +456,12 @@ impl ChunkSection {
     }
 }
 
-struct ChunkMesh {
+pub struct ChunkMesh {
     vertices: Vec<[f32; 3]>,
     uvs: Vec<[f32; 2]>,
     normals: Vec<[f32; 3]>,
+    tiles: Vec<[f32; 4]>,
+    colors: Vec<[f32; 4]>,
     indices: Vec<u32>
 }
 
@@ -186,34 +471,164 @@ impl ChunkMesh {
             vertices: Vec::new(),
             uvs: Vec::new(),
             normals: Vec::new(),
+            tiles: Vec::new(),
+            colors: Vec::new(),
             indices: Vec::new()
         }
     }
 }
 
+/* Opaque blocks are drawn in one mesh and see-through blocks in another, which is blended over everything behind it */
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum MeshLayer {
+    Opaque,
+    Translucent
+}
+
+impl MeshLayer {
+    pub const ALL: [MeshLayer; 2] = [MeshLayer::Opaque, MeshLayer::Translucent];
+
+    fn of(block: BlockId, blocks: &BlockRegistry) -> Self {
+        if blocks.is_transparent(block) { MeshLayer::Translucent } else { MeshLayer::Opaque }
+    }
+}
+
+pub struct SectionMeshes {
+    pub opaque: ChunkMesh,
+    pub translucent: ChunkMesh
+}
+
+impl SectionMeshes {
+    fn new() -> Self {
+        Self { opaque: ChunkMesh::new(), translucent: ChunkMesh::new() }
+    }
+
+    pub fn get(&self, layer: MeshLayer) -> &ChunkMesh {
+        match layer {
+            MeshLayer::Opaque => &self.opaque,
+            MeshLayer::Translucent => &self.translucent
+        }
+    }
+
+    fn get_mut(&mut self, layer: MeshLayer) -> &mut ChunkMesh {
+        match layer {
+            MeshLayer::Opaque => &mut self.opaque,
+            MeshLayer::Translucent => &mut self.translucent
+        }
+    }
+}
+
+/* A section together with the 26 sections surrounding it, used so meshing can look across section and chunk borders */
+pub struct ChunkNeighborhood<'a> {
+    sections: [Option<&'a ChunkSection>; 27]
+}
+
+impl<'a> ChunkNeighborhood<'a> {
+    pub fn new(center: &'a ChunkSection, get_section: impl Fn((i32, i32, i32)) -> Option<&'a ChunkSection>) -> Self {
+        let mut sections = [None; 27];
+        for x in -1..=1 {
+            for y in -1..=1 {
+                for z in -1..=1 {
+                    sections[Self::get_index((x, y, z))] = get_section((center.position.0 + x, center.position.1 + y, center.position.2 + z));
+                }
+            }
+        }
+        sections[Self::get_index((0, 0, 0))] = Some(center);
+        Self { sections }
+    }
+
+    pub fn build_mesh(&self, mode: MeshingMode, blocks: &BlockRegistry, atlas: &BlockAtlas) -> SectionMeshes {
+        let mut builder = ChunkBuilder::new(self, blocks, atlas);
+        builder.build(mode);
+        builder.meshes
+    }
+
+    fn is_buried(&self, blocks: &BlockRegistry) -> bool {
+        [(0, 0, 0), (1, 0, 0), (-1, 0, 0), (0, 1, 0), (0, -1, 0), (0, 0, 1), (0, 0, -1)].iter()
+            .all(|offset| matches!(self.sections[Self::get_index(*offset)], Some(section) if section.is_opaque(blocks)))
+    }
+
+    fn center(&self) -> &'a ChunkSection {
+        self.sections[Self::get_index((0, 0, 0))].unwrap()
+    }
+
+    /* Gets a voxel relative to the center section, missing sections are treated as air */
+    fn get_voxel(&self, position: (i32, i32, i32)) -> Voxel {
+        let length = CHUNK_LENGTH as i32;
+        let offset = (position.0.div_euclid(length), position.1.div_euclid(length), position.2.div_euclid(length));
+        if offset.0.abs() > 1 || offset.1.abs() > 1 || offset.2.abs() > 1 {
+            error!("Attempting to get voxel outside of neighborhood");
+            return Voxel::air();
+        }
+        match self.sections[Self::get_index(offset)] {
+            Some(section) => section.get_voxel((position.0.rem_euclid(length) as u32, position.1.rem_euclid(length) as u32, position.2.rem_euclid(length) as u32)),
+            None => Voxel::air()
+        }
+    }
+
+    /* Gets the light of a voxel relative to the center section, missing sections are treated as open sky */
+    fn get_light(&self, position: (i32, i32, i32)) -> Light {
+        let length = CHUNK_LENGTH as i32;
+        let offset = (position.0.div_euclid(length), position.1.div_euclid(length), position.2.div_euclid(length));
+        match self.sections.get(Self::get_index(offset)) {
+            Some(Some(section)) => section.light.get(ChunkSection::get_index(position.0.rem_euclid(length) as u32, position.1.rem_euclid(length) as u32, position.2.rem_euclid(length) as u32)),
+            _ => Light::SKY
+        }
+    }
+
+    /* Boxes of the block at a position relative to the center section, fences connect to the voxels around them */
+    fn model_boxes(&self, blocks: &BlockRegistry, block: BlockId, position: (i32, i32, i32)) -> Vec<ModelBox> {
+        let model = blocks.model(block);
+        model.boxes(model.connections(blocks, |facing| {
+            let offset = facing.offset();
+            self.get_voxel((position.0 + offset.0, position.1 + offset.1, position.2 + offset.2)).block
+        }))
+    }
+
+    fn get_index(offset: (i32, i32, i32)) -> usize {
+        ((offset.1 + 1) * 9 + (offset.2 + 1) * 3 + offset.0 + 1) as usize
+    }
+}
+
+/* Faces only merge when they share the block, occlusion and light of every corner */
+type GreedyKey = (BlockId, [u8; 4], [Light; 4]);
+
 struct ChunkBuilder<'a> {
-    chunk: &'a mut ChunkSection,
-    index_count: u32
+    neighborhood: &'a ChunkNeighborhood<'a>,
+    blocks: &'a BlockRegistry,
+    atlas: &'a BlockAtlas,
+    meshes: SectionMeshes
 }
 
 impl<'a> ChunkBuilder<'a> {
-    fn new(chunk: &'a mut ChunkSection) -> Self {
+    fn new(neighborhood: &'a ChunkNeighborhood<'a>, blocks: &'a BlockRegistry, atlas: &'a BlockAtlas) -> Self {
         Self {
-            chunk,
-            index_count: 0
+            neighborhood,
+            blocks,
+            atlas,
+            meshes: SectionMeshes::new()
         }
     }
 
-    fn build(&mut self) {
-        self.chunk.chunk_mesh.vertices.clear();
-        self.chunk.chunk_mesh.indices.clear();
-        self.index_count = 0;
-
-        let mut directions = Directions::new();
+    fn build(&mut self, mode: MeshingMode) {
+        // Deep underground sections walled in on every side have no visible faces
+        if self.neighborhood.is_buried(self.blocks) {
+            return;
+        }
+        match mode {
+            MeshingMode::Naive => self.build_naive(),
+            MeshingMode::Greedy => self.build_greedy(),
+        }
+    }
 
+    fn build_naive(&mut self) {
+        let section = self.neighborhood.center();
+        if section.is_empty() {
+            return;
+        }
         for i in 0..CHUNK_VOLUME {
-            let voxel = &self.chunk.voxels[i];
-            if voxel.material == VoxelType::Air {
+            let voxel = section.voxels.get(i);
+            if voxel.block.is_air() {
                 continue;
             }
 
@@ -221,85 +636,604 @@ impl<'a> ChunkBuilder<'a> {
             let y = (i / CHUNK_AREA) as u32;
             let z = (i / CHUNK_LENGTH % CHUNK_LENGTH) as u32;
 
-            directions.update((x as i32, y as i32, z as i32));
+            if self.blocks.model(voxel.block) != BlockModel::Cube {
+                self.add_model(voxel.block, (x, y, z));
+                continue;
+            }
+            for face in &FACES {
+                let adjacent_position = (x as i32 + face.normal[0], y as i32 + face.normal[1], z as i32 + face.normal[2]);
+                self.try_add_face(face, voxel.block, (x, y, z), adjacent_position);
+            }
+        }
+    }
 
-            self.try_add_face(&FRONT_FACE, &FRONT_FACE_UVS, (0., 0., 1.), (x, y, z), directions.front);
-            self.try_add_face(&BACK_FACE, &BACK_FACE_UVS, (0., 0., -1.), (x, y, z), directions.back);
+    /* Sweeps each face direction slice by slice, merging runs of visible faces with the same block, occlusion and light into rectangles */
+    fn build_greedy(&mut self) {
+        let section = self.neighborhood.center();
+        if section.is_empty() {
+            return;
+        }
+        let mut mask: [Option<GreedyKey>; CHUNK_AREA] = [None; CHUNK_AREA];
+
+        for face in &FACES {
+            for slice in 0..CHUNK_LENGTH {
+                for v in 0..CHUNK_LENGTH {
+                    for u in 0..CHUNK_LENGTH {
+                        let mut position = [0; 3];
+                        position[face.axis] = slice as i32;
+                        position[face.u] = u as i32;
+                        position[face.v] = v as i32;
+
+                        let block = section.get_voxel((position[0] as u32, position[1] as u32, position[2] as u32)).block;
+                        let adjacent_position = (position[0] + face.normal[0], position[1] + face.normal[1], position[2] + face.normal[2]);
+                        let visible = !block.is_air() && self.blocks.model(block) == BlockModel::Cube && self.is_face_visible(block, face, &ModelBox::FULL, adjacent_position);
+                        mask[v * CHUNK_LENGTH + u] = if visible {
+                            let block_position = (position[0], position[1], position[2]);
+                            Some((block, self.face_occlusion(face, block_position), self.face_light(face, block_position)))
+                        } else {
+                            None
+                        };
+                    }
+                }
+
+                for v in 0..CHUNK_LENGTH {
+                    let mut u = 0;
+                    while u < CHUNK_LENGTH {
+                        let key = match mask[v * CHUNK_LENGTH + u] {
+                            Some(key) => key,
+                            None => {
+                                u += 1;
+                                continue;
+                            }
+                        };
+
+                        let mut width = 1;
+                        while u + width < CHUNK_LENGTH && mask[v * CHUNK_LENGTH + u + width] == Some(key) {
+                            width += 1;
+                        }
+
+                        let mut height = 1;
+                        'grow: while v + height < CHUNK_LENGTH {
+                            for k in 0..width {
+                                if mask[(v + height) * CHUNK_LENGTH + u + k] != Some(key) {
+                                    break 'grow;
+                                }
+                            }
+                            height += 1;
+                        }
+
+                        for dv in 0..height {
+                            for du in 0..width {
+                                mask[(v + dv) * CHUNK_LENGTH + u + du] = None;
+                            }
+                        }
+
+                        let mut position = [0; 3];
+                        position[face.axis] = slice as u32;
+                        position[face.u] = u as u32;
+                        position[face.v] = v as u32;
+                        let mut size = [1; 3];
+                        size[face.u] = width as u32;
+                        size[face.v] = height as u32;
+
+                        self.add_face(face, key.0, (position[0], position[1], position[2]), (size[0], size[1], size[2]), key.1, key.2);
+                        u += width;
+                    }
+                }
+            }
+        }
+
+        // Blocks with other shapes aren't merged, they are added one by one
+        if section.voxels.any(|voxel| self.blocks.model(voxel.block) != BlockModel::Cube) {
+            for i in 0..CHUNK_VOLUME {
+                let block = section.voxels.get(i).block;
+                if self.blocks.model(block) != BlockModel::Cube {
+                    self.add_model(block, ((i % CHUNK_LENGTH) as u32, (i / CHUNK_AREA) as u32, (i / CHUNK_LENGTH % CHUNK_LENGTH) as u32));
+                }
+            }
+        }
+    }
 
-            self.try_add_face(&RIGHT_FACE, &FRONT_FACE_UVS, (1., 0., 0.), (x, y, z), directions.right);
-            self.try_add_face(&LEFT_FACE, &BACK_FACE_UVS, (-1., 0., 0.), (x, y, z), directions.left);
+    fn try_add_face(&mut self, face: &Face, block: BlockId, block_position: (u32, u32, u32), adjacent_position: (i32, i32, i32)) {
+        if !self.is_face_visible(block, face, &ModelBox::FULL, adjacent_position) {
+            return;
+        }
+        let position = (block_position.0 as i32, block_position.1 as i32, block_position.2 as i32);
+        self.add_face(face, block, block_position, (1, 1, 1), self.face_occlusion(face, position), self.face_light(face, position));
+    }
 
-            self.try_add_face(&TOP_FACE, &FRONT_FACE_UVS, (0., 1., 0.), (x, y, z), directions.top);
-            self.try_add_face(&BOTTOM_FACE, &BACK_FACE_UVS, (0., -1., 0.), (x, y, z), directions.bottom);
+    /* Faces show through see-through neighbors, except between two of the same block so the inside of a lake or a glass wall
+       isn't drawn. Otherwise only the part of the face the neighbor's shape leaves uncovered shows */
+    fn is_face_visible(&self, block: BlockId, face: &Face, face_box: &ModelBox, adjacent_position: (i32, i32, i32)) -> bool {
+        let adjacent = self.neighborhood.get_voxel(adjacent_position).block;
+        if self.blocks.is_transparent(adjacent) && adjacent != block {
+            return true;
         }
+        if self.blocks.model(adjacent) == BlockModel::Cube {
+            return false;
+        }
+        let plane = if face.normal[face.axis] > 0 { 0. } else { 1. };
+        !self.neighborhood.model_boxes(self.blocks, adjacent, adjacent_position).iter().any(|model_box| model_box.covers(face_box, face.axis, plane))
     }
 
-    fn try_add_face(&mut self, vertices: &[[f32; 3]; 4], uvs: &[[f32; 2]; 4], normal: (f32, f32, f32), block_position: (u32, u32, u32), adjacent_position: (i32, i32, i32)) {
-        if adjacent_position.0 > 0 &&
-            adjacent_position.1 > 0 &&
-            adjacent_position.2 > 0 &&
-            ChunkSection::in_bounds(adjacent_position.0 as u32, adjacent_position.1 as u32, adjacent_position.2 as u32) {
-            if self.chunk.get_voxel((adjacent_position.0 as u32, adjacent_position.1 as u32, adjacent_position.2 as u32)).material != VoxelType::Air {
-                return;
+    /* Adds the faces of every box of a shaped block that aren't covered by its neighbors or by its own other boxes */
+    fn add_model(&mut self, block: BlockId, block_position: (u32, u32, u32)) {
+        let position = (block_position.0 as i32, block_position.1 as i32, block_position.2 as i32);
+        if self.blocks.model(block) == BlockModel::Cross {
+            self.add_cross(block, block_position);
+            return;
+        }
+        let boxes = self.neighborhood.model_boxes(self.blocks, block, position);
+        for (i, model_box) in boxes.iter().enumerate() {
+            for face in &FACES {
+                let plane = if face.normal[face.axis] > 0 { model_box.max[face.axis] } else { model_box.min[face.axis] };
+                let visible = if plane == 0. || plane == 1. {
+                    let adjacent_position = (position.0 + face.normal[0], position.1 + face.normal[1], position.2 + face.normal[2]);
+                    self.is_face_visible(block, face, model_box, adjacent_position)
+                } else {
+                    !boxes.iter().enumerate().any(|(j, other)| i != j && other.covers(model_box, face.axis, plane))
+                };
+                if visible {
+                    self.add_box_face(face, block, block_position, model_box);
+                }
             }
         }
+    }
+
+    /* Box faces take the light of the voxel in front of the voxel side they face, the same as full faces */
+    fn add_box_face(&mut self, face: &Face, block: BlockId, block_position: (u32, u32, u32), model_box: &ModelBox) {
+        let origin = Self::vertex_origin(block_position);
+        let position = (block_position.0 as i32, block_position.1 as i32, block_position.2 as i32);
+        let mut corners = [[0.; 3]; 4];
+        let mut uvs = [[0.; 2]; 4];
+        for (i, (vertex, uv)) in face.vertices.iter().zip(FACE_UVS).enumerate() {
+            let point = [0, 1, 2].map(|axis| model_box.min[axis] + vertex[axis] * (model_box.max[axis] - model_box.min[axis]));
+            corners[i] = [0, 1, 2].map(|axis| origin[axis] + point[axis]);
+            // The box shows the part of the texture it would cover on a full face
+            let along = |axis: usize, uv: f32| if (vertex[axis] > 0.) == (uv > 0.) { point[axis] } else { 1. - point[axis] };
+            uvs[i] = [along(face.u, uv[0]), along(face.v, uv[1])];
+        }
+        let colors = vertex_colors(self.face_occlusion(face, position), self.face_light(face, position));
+        self.add_quad(block, face.block_face, face.normal.map(|normal| normal as f32), corners, uvs, colors);
+    }
 
-        for i in 0..4 {
-            let vertex = vertices[i];
-            self.chunk.chunk_mesh.vertices.push([
-                vertex[0] + (block_position.0 as i32 + self.chunk.position.0 * CHUNK_LENGTH as i32) as f32,
-                vertex[1] + (block_position.1 as i32 + self.chunk.position.1 * CHUNK_LENGTH as i32) as f32,
-                vertex[2] + (block_position.2 as i32 + self.chunk.position.2 * CHUNK_LENGTH as i32) as f32,
-            ]);
-            self.chunk.chunk_mesh.uvs.push(uvs[i]);
-            self.chunk.chunk_mesh.normals.push([normal.0, normal.1, normal.2]);
+    /* Two quads crossing diagonally through the voxel, each drawn from both sides and lit by the voxel itself */
+    fn add_cross(&mut self, block: BlockId, block_position: (u32, u32, u32)) {
+        let origin = Self::vertex_origin(block_position);
+        let light = [self.neighborhood.get_light((block_position.0 as i32, block_position.1 as i32, block_position.2 as i32)); 4];
+        for [(start_x, start_z), (end_x, end_z)] in [[(0., 0.), (1., 1.)], [(0., 1.), (1., 0.)], [(1., 1.), (0., 0.)], [(1., 0.), (0., 1.)]] {
+            let corners = [[start_x, 0., start_z], [end_x, 0., end_z], [end_x, 1., end_z], [start_x, 1., start_z]]
+                .map(|corner: [f32; 3]| [origin[0] + corner[0], origin[1] + corner[1], origin[2] + corner[2]]);
+            // Lit as if facing up so plants match the ground they grow on
+            self.add_quad(block, BlockFace::Side, [0., 1., 0.], corners, FACE_UVS, vertex_colors([3; 4], light));
         }
+    }
 
-        self.chunk.chunk_mesh.indices.extend([
-            self.index_count, self.index_count + 1, self.index_count + 2,
-            self.index_count + 2, self.index_count + 3, self.index_count
-        ]);
+    /* Vertices are relative to the section's origin, its entity's transform places them in the world */
+    fn vertex_origin(block_position: (u32, u32, u32)) -> [f32; 3] {
+        [block_position.0 as f32, block_position.1 as f32, block_position.2 as f32]
+    }
 
-        self.index_count += 4;
+    /* Occlusion of each face corner from 0 (darkest) to 3 (open), counted from the two edge voxels and the corner voxel touching it in the layer in front of the face */
+    fn face_occlusion(&self, face: &Face, block_position: (i32, i32, i32)) -> [u8; 4] {
+        let mut occlusion = [0; 4];
+        for (i, [_, side_u, side_v, corner]) in Self::corner_samples(face, block_position).into_iter().enumerate() {
+            let (side_u, side_v, corner) = (self.is_occluder(side_u), self.is_occluder(side_v), self.is_occluder(corner));
+            // Two occluding sides hide the corner voxel, so the vertex is fully dark either way
+            occlusion[i] = if side_u && side_v { 0 } else { 3 - side_u as u8 - side_v as u8 - corner as u8 };
+        }
+        occlusion
     }
+
+    /* Smooth light of each face corner, averaged over the open voxels among the ones the corner touches in front of the face */
+    fn face_light(&self, face: &Face, block_position: (i32, i32, i32)) -> [Light; 4] {
+        Self::corner_samples(face, block_position).map(|samples| {
+            let open: Vec<Light> = samples.iter()
+                .filter(|sample| !self.is_occluder(**sample))
+                .map(|sample| self.neighborhood.get_light((sample[0], sample[1], sample[2])))
+                .collect();
+            // Light leaking diagonally past two occluding sides would show up as bright spots in dark corners
+            let open = if self.is_occluder(samples[1]) && self.is_occluder(samples[2]) { &open[..open.len().min(1)] } else { &open[..] };
+            Light::average(open)
+        })
+    }
+
+    /* The voxel in front of the face, then the two edge voxels and the corner voxel touching each face corner in that layer */
+    fn corner_samples(face: &Face, block_position: (i32, i32, i32)) -> [[[i32; 3]; 4]; 4] {
+        let front = [block_position.0 + face.normal[0], block_position.1 + face.normal[1], block_position.2 + face.normal[2]];
+        face.vertices.map(|vertex| {
+            let mut side_u = front;
+            side_u[face.u] += if vertex[face.u] > 0. { 1 } else { -1 };
+            let mut side_v = front;
+            side_v[face.v] += if vertex[face.v] > 0. { 1 } else { -1 };
+            let mut corner = side_u;
+            corner[face.v] = side_v[face.v];
+            [front, side_u, side_v, corner]
+        })
+    }
+
+    fn is_occluder(&self, position: [i32; 3]) -> bool {
+        self.blocks.is_opaque(self.neighborhood.get_voxel((position[0], position[1], position[2])).block)
+    }
+
+    /* Adds a quad covering `size` voxels starting at `block_position`, stretching the unit face vertices to fit while the uvs count blocks so the texture repeats */
+    fn add_face(&mut self, face: &Face, block: BlockId, block_position: (u32, u32, u32), size: (u32, u32, u32), occlusion: [u8; 4], light: [Light; 4]) {
+        let size = [size.0 as f32, size.1 as f32, size.2 as f32];
+        let origin = Self::vertex_origin(block_position);
+        let corners = face.vertices.map(|vertex| [0, 1, 2].map(|axis| vertex[axis] * size[axis] + origin[axis]));
+        let uvs = FACE_UVS.map(|uv| [uv[0] * size[face.u], uv[1] * size[face.v]]);
+        self.add_quad(block, face.block_face, face.normal.map(|normal| normal as f32), corners, uvs, vertex_colors(occlusion, light));
+    }
+
+    /* Adds a quad to the mesh of the block's layer, its corners listed counter clockwise as seen from the front */
+    fn add_quad(&mut self, block: BlockId, block_face: BlockFace, normal: [f32; 3], corners: [[f32; 3]; 4], uvs: [[f32; 2]; 4], colors: [[f32; 4]; 4]) {
+        let tile = self.atlas.tile(block, block_face);
+        let mesh = self.meshes.get_mut(MeshLayer::of(block, self.blocks));
+        let index_count = mesh.vertices.len() as u32;
+        mesh.vertices.extend(corners);
+        mesh.uvs.extend(uvs);
+        mesh.normals.extend([normal; 4]);
+        mesh.tiles.extend([tile; 4]);
+        mesh.colors.extend(colors);
+
+        // Splitting the quad along the brighter diagonal keeps the shading symmetric instead of smearing a dark corner across both triangles
+        let brightness = colors.map(|color| color.into_iter().fold(0., f32::max));
+        let corners = if brightness[0] + brightness[2] >= brightness[1] + brightness[3] { [0, 1, 2, 2, 3, 0] } else { [1, 2, 3, 3, 0, 1] };
+        mesh.indices.extend(corners.map(|corner| index_count + corner));
+    }
+}
+
+/* Block light goes in rgb and skylight in alpha so the shader can scale the sky with the sun, both darkened by occlusion */
+fn vertex_colors(occlusion: [u8; 4], light: [Light; 4]) -> [[f32; 4]; 4] {
+    [0, 1, 2, 3].map(|corner| {
+        let occlusion = OCCLUSION_BRIGHTNESS[occlusion[corner] as usize];
+        let [red, green, blue] = light[corner].block().map(|level| LIGHT_BRIGHTNESS[level as usize] * occlusion);
+        [red, green, blue, LIGHT_BRIGHTNESS[light[corner].sky() as usize] * occlusion]
+    })
 }
 
+struct Face {
+    vertices: &'static [[f32; 3]; 4],
+    block_face: BlockFace,
+    normal: [i32; 3],
+    /* Axis the face points along, followed by the two axes spanning the face */
+    axis: usize,
+    u: usize,
+    v: usize,
+}
+
+const FACES: [Face; 6] = [
+    Face { vertices: &FRONT_FACE, block_face: BlockFace::Side, normal: [0, 0, 1], axis: 2, u: 0, v: 1 },
+    Face { vertices: &BACK_FACE, block_face: BlockFace::Side, normal: [0, 0, -1], axis: 2, u: 0, v: 1 },
+    Face { vertices: &RIGHT_FACE, block_face: BlockFace::Side, normal: [1, 0, 0], axis: 0, u: 2, v: 1 },
+    Face { vertices: &LEFT_FACE, block_face: BlockFace::Side, normal: [-1, 0, 0], axis: 0, u: 2, v: 1 },
+    Face { vertices: &TOP_FACE, block_face: BlockFace::Top, normal: [0, 1, 0], axis: 1, u: 0, v: 2 },
+    Face { vertices: &BOTTOM_FACE, block_face: BlockFace::Bottom, normal: [0, -1, 0], axis: 1, u: 0, v: 2 },
+];
+
+/* Vertex brightness for each ambient occlusion level */
+const OCCLUSION_BRIGHTNESS: [f32; 4] = [0.45, 0.65, 0.83, 1.];
+
+/* Vertex brightness for each light level, every level is 80% as bright as the one above */
+const LIGHT_BRIGHTNESS: [f32; MAX_LIGHT as usize + 1] = [
+    0.035, 0.044, 0.055, 0.069, 0.086, 0.107, 0.134, 0.168, 0.21, 0.262, 0.328, 0.41, 0.512, 0.64, 0.8, 1.
+];
+
+/* Every face lists its vertices counter clockwise from the bottom left corner as seen from outside, so they share uvs with v pointing down the texture */
+const FACE_UVS: [[f32; 2]; 4] = [
+    [0., 1.], [1., 1.], [1., 0.], [0., 0.]
+];
+
 const FRONT_FACE: [[f32; 3]; 4] = [
     [0., 0., 1.], [1., 0., 1.], [1., 1., 1.], [0., 1., 1.]
 ];
-const FRONT_FACE_UVS: [[f32; 2]; 4] = [
-    [0., 0.], [1., 0.], [1., 1.], [1., 1.]
-];
 const BACK_FACE: [[f32; 3]; 4] = [
     [1., 0., 0.], [0., 0., 0.], [0., 1., 0.], [1., 1., 0.]
 ];
-const BACK_FACE_UVS: [[f32; 2]; 4] = [
-    [0., 0.], [1., 0.], [1., 1.], [1., 1.]
-];
 
 const LEFT_FACE: [[f32; 3]; 4] = [
     [0., 0., 0.], [0., 0., 1.], [0., 1., 1.], [0., 1., 0.]
 ];
-const LEFT_FACE_UVS: [[f32; 2]; 4] = [
-    [0., 0.], [1., 0.], [1., 1.], [1., 1.]
-];
 const RIGHT_FACE: [[f32; 3]; 4] = [
     [1., 0., 1.], [1., 0., 0.], [1., 1., 0.], [1., 1., 1.]
 ];
-const RIGHT_FACE_UVS: [[f32; 2]; 4] = [
-    [0., 0.], [1., 0.], [1., 1.], [1., 1.]
-];
 
 const TOP_FACE: [[f32; 3]; 4] = [
     [0., 1., 1.], [1., 1., 1.], [1., 1., 0.], [0., 1., 0.]
 ];
-const TOP_FACE_UVS: [[f32; 2]; 4] = [
-    [0., 0.], [1., 0.], [1., 1.], [1., 1.]
-];
 const BOTTOM_FACE: [[f32; 3]; 4] = [
     [0., 0., 0.], [1., 0., 0.], [1., 0., 1.], [0., 0., 1.]
 ];
-const BOTTOM_FACE_UVS: [[f32; 2]; 4] = [
-    [0., 0.], [1., 0.], [1., 1.], [1., 1.]
-];
 
+
+#[cfg(test)]
+impl Chunk {
+    pub(crate) fn push_overflow(&mut self, position: (i32, i32, i32), block: BlockId) {
+        self.overflow.push((position, block));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::game::world::block::test_blocks::{self, DIRT, GLASS, GRASS, PLANT, SLAB, STONE, WATER};
+
+    fn fill_section(position: (i32, i32, i32), fill: impl Fn(u32, u32, u32) -> Option<BlockId>) -> ChunkSection {
+        let mut section = ChunkSection::new(position);
+        for x in 0..CHUNK_LENGTH as u32 {
+            for y in 0..CHUNK_LENGTH as u32 {
+                for z in 0..CHUNK_LENGTH as u32 {
+                    if let Some(block) = fill(x, y, z) {
+                        section.set_voxel((x, y, z), block);
+                    }
+                }
+            }
+        }
+        section.voxels.optimize();
+        section
+    }
+
+    fn build_section(mode: MeshingMode, fill: impl Fn(u32, u32, u32) -> Option<BlockId>) -> ChunkSection {
+        let mut section = fill_section((0, 0, 0), fill);
+        section.meshes = ChunkNeighborhood::new(&section, |_| None).build_mesh(mode, &test_blocks::registry(), &BlockAtlas::default());
+        section
+    }
+
+    #[test]
+    fn greedy_merges_flat_layer() {
+        let fill = |_, y, _| if y == 4 { Some(GRASS) } else { None };
+        let naive = build_section(MeshingMode::Naive, fill);
+        let greedy = build_section(MeshingMode::Greedy, fill);
+
+        assert_eq!(naive.meshes.opaque.vertices.len(), (CHUNK_AREA * 2 + CHUNK_LENGTH * 4) * 4);
+        assert_eq!(greedy.meshes.opaque.vertices.len(), 6 * 4);
+        assert_eq!(greedy.meshes.opaque.indices.len(), 6 * 6);
+    }
+
+    #[test]
+    fn greedy_splits_materials() {
+        let fill = |x, y, _| if y == 4 { Some(if x < 8 { GRASS } else { DIRT }) } else { None };
+        let greedy = build_section(MeshingMode::Greedy, fill);
+
+        // Top and bottom split in two, left and right untouched, front and back split in two
+        assert_eq!(greedy.meshes.opaque.vertices.len(), (2 + 2 + 1 + 1 + 2 + 2) * 4);
+    }
+
+    #[test]
+    fn greedy_covers_same_area_as_naive() {
+        let fill = |x, y, z| if y <= (x + z) / 4 { Some(DIRT) } else { None };
+        let naive = build_section(MeshingMode::Naive, fill);
+        let greedy = build_section(MeshingMode::Greedy, fill);
+
+        let area = |mesh: &ChunkMesh| -> f32 {
+            mesh.indices.chunks(3).map(|triangle| {
+                let a = Vec3::from(mesh.vertices[triangle[0] as usize]);
+                let b = Vec3::from(mesh.vertices[triangle[1] as usize]);
+                let c = Vec3::from(mesh.vertices[triangle[2] as usize]);
+                (b - a).cross(c - a).length() / 2.
+            }).sum()
+        };
+        assert!(greedy.meshes.opaque.vertices.len() < naive.meshes.opaque.vertices.len());
+        assert!((area(&greedy.meshes.opaque) - area(&naive.meshes.opaque)).abs() < 0.01);
+    }
+
+    #[test]
+    fn culls_faces_against_neighbor_sections() {
+        let blocks = test_blocks::registry();
+        let solid = |_, _, _| Some(STONE);
+        let section = fill_section((0, 0, 0), solid);
+        let above = fill_section((0, 1, 0), solid);
+        let right = fill_section((1, 0, 0), solid);
+
+        let neighborhood = ChunkNeighborhood::new(&section, |position| match position {
+            (0, 1, 0) => Some(&above),
+            (1, 0, 0) => Some(&right),
+            _ => None
+        });
+        for mode in [MeshingMode::Naive, MeshingMode::Greedy] {
+            let mesh = neighborhood.build_mesh(mode, &blocks, &BlockAtlas::default()).opaque;
+            assert!(mesh.normals.iter().all(|normal| normal[1] <= 0. && normal[0] <= 0.));
+            assert_eq!(mesh.normals.iter().filter(|normal| normal[1] < 0.).count(), if mode == MeshingMode::Naive { CHUNK_AREA * 4 } else { 4 });
+        }
+    }
+
+    fn top_face_occlusion(section: &ChunkSection, position: (i32, i32, i32)) -> [u8; 4] {
+        let blocks = test_blocks::registry();
+        let atlas = BlockAtlas::default();
+        let neighborhood = ChunkNeighborhood::new(section, |_| None);
+        ChunkBuilder::new(&neighborhood, &blocks, &atlas).face_occlusion(&FACES[4], position)
+    }
+
+    #[test]
+    fn occlusion_darkens_corners_next_to_blocks() {
+        let floor = |_, y, _| if y == 0 { Some(STONE) } else { None };
+        let mut section = fill_section((0, 0, 0), floor);
+        assert_eq!(top_face_occlusion(&section, (5, 0, 5)), [3; 4]);
+
+        // A wall along -x darkens the two corners touching it, an extra block along +z makes the shared corner fully dark
+        section.set_voxel((4, 1, 5), STONE);
+        assert_eq!(top_face_occlusion(&section, (5, 0, 5)), [2, 3, 3, 2]);
+        section.set_voxel((5, 1, 6), STONE);
+        assert_eq!(top_face_occlusion(&section, (5, 0, 5)), [0, 2, 3, 2]);
+
+        // A lone diagonal block only touches one corner
+        let mut section = fill_section((0, 0, 0), floor);
+        section.set_voxel((6, 1, 4), STONE);
+        assert_eq!(top_face_occlusion(&section, (5, 0, 5)), [3, 3, 2, 3]);
+    }
+
+    #[test]
+    fn occlusion_reads_neighbor_sections() {
+        let section = fill_section((0, 0, 0), |_, y, _| if y == 15 { Some(STONE) } else { None });
+        let above = fill_section((0, 1, 0), |x, y, _| if x == 4 && y == 0 { Some(STONE) } else { None });
+        let blocks = test_blocks::registry();
+        let atlas = BlockAtlas::default();
+        let neighborhood = ChunkNeighborhood::new(&section, |position| if position == (0, 1, 0) { Some(&above) } else { None });
+        assert_eq!(ChunkBuilder::new(&neighborhood, &blocks, &atlas).face_occlusion(&FACES[4], (5, 15, 5)), [1, 3, 3, 1]);
+    }
+
+    #[test]
+    fn occluded_quads_flip_towards_bright_diagonal() {
+        let section = ChunkSection::new((0, 0, 0));
+        let blocks = test_blocks::registry();
+        let atlas = BlockAtlas::default();
+        let neighborhood = ChunkNeighborhood::new(&section, |_| None);
+        let mut builder = ChunkBuilder::new(&neighborhood, &blocks, &atlas);
+        builder.add_face(&FACES[4], STONE, (5, 0, 5), (1, 1, 1), [3, 3, 2, 3], [Light::SKY; 4]);
+        builder.add_face(&FACES[4], STONE, (5, 0, 5), (1, 1, 1), [3, 3, 3, 3], [Light::SKY; 4]);
+
+        assert_eq!(&builder.meshes.opaque.indices[..6], &[1, 2, 3, 3, 0, 1]);
+        assert_eq!(&builder.meshes.opaque.indices[6..], &[4, 5, 6, 6, 7, 4]);
+        let block = LIGHT_BRIGHTNESS[0] * OCCLUSION_BRIGHTNESS[2];
+        assert_eq!(builder.meshes.opaque.colors[2], [block, block, block, OCCLUSION_BRIGHTNESS[2]]);
+    }
+
+    #[test]
+    fn greedy_keeps_occlusion_separate() {
+        let mut section = fill_section((0, 0, 0), |_, y, _| if y == 0 { Some(STONE) } else { None });
+        section.set_voxel((8, 1, 8), STONE);
+        let blocks = test_blocks::registry();
+        let mesh = ChunkNeighborhood::new(&section, |_| None).build_mesh(MeshingMode::Greedy, &blocks, &BlockAtlas::default()).opaque;
+
+        // The floor top around the block can no longer be a single quad, and every merged corner keeps its own shading
+        let floor_tops = mesh.normals.iter().zip(&mesh.vertices).filter(|(normal, vertex)| normal[1] > 0. && vertex[1] == 1.).count() / 4;
+        assert!(floor_tops > 2);
+        assert!(mesh.colors.iter().any(|color| color[3] < 1.));
+    }
+
+    #[test]
+    fn column_grows_in_both_directions() {
+        let mut chunk = Chunk::new((0, 0));
+        chunk.set_voxel((0, 40, 0), STONE);
+        assert_eq!(chunk.section_range(), 2..3);
+        chunk.set_voxel((0, -1, 0), DIRT);
+        assert_eq!(chunk.section_range(), -1..3);
+
+        assert!(chunk.get_voxel((0, 40, 0)).block == STONE);
+        assert!(chunk.get_voxel((0, -1, 0)).block == DIRT);
+        assert!(chunk.get_voxel((0, -100, 0)).block.is_air());
+        assert_eq!(chunk.get_section(-1).unwrap().position, (0, -1, 0));
+    }
+
+    #[test]
+    fn see_through_blocks_mesh_separately() {
+        let section = build_section(MeshingMode::Naive, |x, y, z| match y {
+            0 => Some(STONE),
+            1 | 2 => Some(WATER),
+            3 if (x, z) == (4, 4) => Some(GLASS),
+            _ => None
+        });
+        let quads = |mesh: &ChunkMesh, normal_y: f32, height: f32| {
+            mesh.normals.iter().zip(&mesh.vertices).filter(|(normal, vertex)| normal[1] == normal_y && vertex[1] == height).count() / 4
+        };
+        // The stone floor shows through the water above it
+        assert_eq!(quads(&section.meshes.opaque, 1., 1.), CHUNK_AREA);
+        // Water faces between two water voxels are culled, faces against the glass are not
+        let translucent = &section.meshes.translucent;
+        assert_eq!(quads(translucent, 1., 2.) + quads(translucent, -1., 2.), 0);
+        assert_eq!(quads(translucent, 1., 3.), CHUNK_AREA);
+        assert_eq!(quads(translucent, -1., 3.), 1);
+        assert_eq!(quads(translucent, 1., 4.), 1);
+    }
+
+    #[test]
+    fn shaped_blocks_cull_the_faces_they_cover() {
+        for mode in [MeshingMode::Naive, MeshingMode::Greedy] {
+            let section = build_section(mode, |x, y, z| match (x, y, z) {
+                (3, 0, 4) => Some(STONE),
+                (4, 0, 4) | (5, 0, 4) => Some(SLAB),
+                (8, 0, 8) => Some(PLANT),
+                _ => None
+            });
+            let quads = |mesh: &ChunkMesh, normal_x: f32, x: f32| {
+                mesh.normals.iter().zip(&mesh.vertices).filter(|(normal, vertex)| normal[0] == normal_x && vertex[0] == x).count() / 4
+            };
+            let opaque = &section.meshes.opaque;
+            // The two slabs hide each other's sides, the stone only shows above the slab it touches
+            assert_eq!(quads(opaque, 1., 5.) + quads(opaque, -1., 5.), 0);
+            assert_eq!(quads(opaque, 1., 4.), 1);
+            assert_eq!(quads(opaque, -1., 4.), 0);
+            assert_eq!(opaque.normals.iter().zip(&opaque.vertices).filter(|(normal, vertex)| normal[1] > 0. && vertex[1] == 0.5).count(), 2 * 4);
+            // Crossed quads are drawn from both sides
+            assert_eq!(section.meshes.translucent.indices.len(), 4 * 6);
+        }
+    }
+
+    #[test]
+    fn buried_sections_are_skipped() {
+        let blocks = test_blocks::registry();
+        let solid = |_, _, _| Some(STONE);
+        let sections: Vec<ChunkSection> = (0..7).map(|_| fill_section((0, 0, 0), solid)).collect();
+        let neighborhood = ChunkNeighborhood::new(&sections[0], |position| match position {
+            (1, 0, 0) => Some(&sections[1]),
+            (-1, 0, 0) => Some(&sections[2]),
+            (0, 1, 0) => Some(&sections[3]),
+            (0, -1, 0) => Some(&sections[4]),
+            (0, 0, 1) => Some(&sections[5]),
+            (0, 0, -1) => Some(&sections[6]),
+            _ => None
+        });
+        assert!(neighborhood.is_buried(&blocks));
+        assert!(neighborhood.build_mesh(MeshingMode::Naive, &blocks, &BlockAtlas::default()).opaque.indices.is_empty());
+
+        let open = ChunkNeighborhood::new(&sections[0], |position| if position == (1, 0, 0) { Some(&sections[1]) } else { None });
+        assert!(!open.is_buried(&blocks));
+    }
+
+    #[test]
+    fn decorations_overflow_into_neighbors() {
+        let blocks = TerrainBlocks { grass: GRASS, log: BlockId(4), leaves: BlockId(5), ..default() };
+        let mut chunk = Chunk::new((-1, 0));
+        for x in 0..CHUNK_LENGTH as u32 {
+            for z in 0..CHUNK_LENGTH as u32 {
+                chunk.set_voxel((x, 3, z), GRASS);
+            }
+        }
+        chunk.set_voxel((14, 6, 8), STONE);
+        chunk.place_decoration((15, 8), Decoration::Tree { height: 4 }, &blocks);
+
+        // The canopy's east half lands in the chunk at x 0
+        assert!(chunk.get_voxel((15, 4, 8)).block == blocks.log);
+        assert!(chunk.get_voxel((14, 6, 8)).block == STONE);
+        assert!(chunk.get_voxel((13, 6, 8)).block == blocks.leaves);
+        assert!(chunk.overflow_into((0, 0)).any(|(position, block)| position == (1, 6, 8) && block == blocks.leaves));
+        assert_eq!(chunk.overflow_into((-2, 0)).count(), 0);
+        assert_eq!(chunk.overflow_into((0, 0)).count(), chunk.overflow.len());
+    }
+
+    #[test]
+    fn serialized_chunk_round_trips() {
+        let mut chunk = Chunk::new((3, -2));
+        chunk.set_voxel((1, -18, 3), STONE);
+        chunk.set_voxel((15, 20, 0), GRASS);
+        chunk.overflow.push(((64, -5, -33), STONE));
+        chunk.record_received_overflow((4, -2));
+        chunk.terrain_generated = true;
+
+        let loaded = Chunk::deserialize((3, -2), &chunk.serialize()).unwrap();
+        assert_eq!(loaded.overflow, chunk.overflow);
+        assert!(loaded.has_received_overflow((4, -2)) && !loaded.has_received_overflow((2, -2)));
+        assert_eq!(loaded.section_range(), -2..2);
+        assert!(loaded.get_voxel((1, -18, 3)).block == STONE);
+        assert!(loaded.get_voxel((15, 20, 0)).block == GRASS);
+        assert!(loaded.get_voxel((0, 0, 0)).block.is_air());
+        assert!(Chunk::deserialize((3, -2), &[CHUNK_FORMAT_VERSION, 1, 0, 0]).is_none());
+    }
+
+    #[test]
+    fn chunks_saved_before_overflow_still_load() {
+        let mut chunk = Chunk::new((3, -2));
+        chunk.set_voxel((1, -18, 3), STONE);
+        let mut bytes = chunk.serialize();
+        // Version 2 is the same layout without the overflow trailers
+        bytes[0] = 2;
+        bytes.truncate(bytes.len() - 8);
+
+        let loaded = Chunk::deserialize((3, -2), &bytes).unwrap();
+        assert!(loaded.overflow.is_empty());
+        assert!(loaded.get_voxel((1, -18, 3)).block == STONE);
+        bytes[0] = 1;
+        assert!(Chunk::deserialize((3, -2), &bytes).is_none());
+    }
+}
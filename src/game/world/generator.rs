@@ -1,22 +1,649 @@
-use noise::{NoiseFn, OpenSimplex, Perlin, Seedable};
+use std::ops::Range;
+use bevy::prelude::*;
+use noise::{NoiseFn, OpenSimplex, Seedable};
+use serde::{Deserialize, Serialize};
+use crate::game::world::block::{BlockId, BlockRegistry};
+use crate::game::world::chunk::CHUNK_LENGTH;
+
+/* The registry blocks the generator places, resolved by name once so generation tasks don't need the registry */
+#[derive(Clone, Default)]
+pub struct TerrainBlocks {
+    pub grass: BlockId,
+    pub dirt: BlockId,
+    pub stone: BlockId,
+    pub sand: BlockId,
+    pub snow: BlockId,
+    pub water: BlockId,
+    pub log: BlockId,
+    pub leaves: BlockId,
+    pub cobblestone: BlockId,
+    /* Block of every ore in the generator settings, in the same order */
+    pub ores: Vec<BlockId>
+}
+
+impl TerrainBlocks {
+    pub fn from_registry(blocks: &BlockRegistry, settings: &GeneratorSettings) -> Self {
+        let resolve = |name| blocks.by_name(name).unwrap_or_else(|| {
+            error!("Terrain block {} missing from registry", name);
+            BlockId::AIR
+        });
+        Self {
+            grass: resolve("grass"),
+            dirt: resolve("dirt"),
+            stone: resolve("stone"),
+            sand: resolve("sand"),
+            snow: resolve("snow"),
+            water: resolve("water"),
+            log: resolve("log"),
+            leaves: resolve("leaves"),
+            cobblestone: resolve("cobblestone"),
+            ores: settings.ores.iter().map(|ore| resolve(&ore.block)).collect()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Biome {
+    Ocean,
+    Plains,
+    Desert,
+    Mountains,
+    Tundra
+}
+
+impl Biome {
+    pub fn surface_block(&self, blocks: &TerrainBlocks) -> BlockId {
+        match self {
+            Biome::Ocean | Biome::Desert => blocks.sand,
+            Biome::Plains => blocks.grass,
+            Biome::Mountains => blocks.stone,
+            Biome::Tundra => blocks.snow
+        }
+    }
+
+    pub fn subsurface_block(&self, blocks: &TerrainBlocks) -> BlockId {
+        match self {
+            Biome::Ocean | Biome::Desert => blocks.sand,
+            Biome::Plains | Biome::Tundra => blocks.dirt,
+            Biome::Mountains => blocks.stone
+        }
+    }
+
+    /* Rolls what, if anything, grows at one of the spots tried in every chunk */
+    fn decoration(&self, random: &mut PlacementRandom) -> Option<Decoration> {
+        let roll = random.next_f32();
+        match self {
+            Biome::Ocean => None,
+            Biome::Plains if roll < 0.25 => Some(Decoration::Tree { height: random.range(4..7) }),
+            Biome::Tundra if roll < 0.15 => Some(Decoration::Tree { height: random.range(3..5) }),
+            Biome::Mountains if roll < 0.1 => Some(Decoration::Boulder { radius: random.range(1..3) }),
+            Biome::Plains if roll < 0.28 => Some(Decoration::Boulder { radius: 1 }),
+            Biome::Plains | Biome::Desert if roll > 0.995 => Some(Decoration::Ruin),
+            _ => None
+        }
+    }
+}
+
+/* Cobblestone remains of a small hut, layers from the ground up with rows along z and columns along x */
+const RUIN: [[&str; 5]; 3] = [
+    ["ccccc", "c...c", "c...c", "c...c", "cc.cc"],
+    ["cc.cc", "c...c", "....c", "c...c", "c...c"],
+    ["c..cc", ".....", "....c", ".....", "c...."]
+];
+
+/* Features placed on the surface once the terrain is generated, they may reach into neighboring chunks */
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Decoration {
+    Tree { height: i32 },
+    Boulder { radius: i32 },
+    Ruin
+}
+
+impl Decoration {
+    /* Furthest any decoration block lies from the column it grows from */
+    pub const MAX_REACH: i32 = 2;
+
+    pub fn can_grow_on(&self, block: BlockId, blocks: &TerrainBlocks) -> bool {
+        match self {
+            Decoration::Tree { .. } => block == blocks.grass || block == blocks.snow || block == blocks.dirt,
+            Decoration::Boulder { .. } => block != blocks.water,
+            Decoration::Ruin => block == blocks.grass || block == blocks.sand
+        }
+    }
+
+    /* Blocks relative to the first air block above the surface, earlier blocks win where they overlap */
+    pub fn blocks(&self, blocks: &TerrainBlocks) -> Vec<((i32, i32, i32), BlockId)> {
+        let mut placed = Vec::new();
+        match *self {
+            Decoration::Tree { height } => {
+                placed.extend((0..height).map(|y| ((0, y, 0), blocks.log)));
+                for y in height - 2..=height {
+                    let radius: i32 = if y < height { 2 } else { 1 };
+                    for x in -radius..=radius {
+                        for z in -radius..=radius {
+                            // Trimming the corners rounds off the canopy
+                            if x.abs() == radius && z.abs() == radius {
+                                continue;
+                            }
+                            placed.push(((x, y, z), blocks.leaves));
+                        }
+                    }
+                }
+            }
+            Decoration::Boulder { radius } => {
+                for x in -radius..=radius {
+                    for y in -radius..=radius {
+                        for z in -radius..=radius {
+                            if x * x + y * y + z * z <= radius * radius + radius {
+                                placed.push(((x, y, z), blocks.stone));
+                            }
+                        }
+                    }
+                }
+            }
+            Decoration::Ruin => {
+                for (y, layer) in RUIN.iter().enumerate() {
+                    for (z, row) in layer.iter().enumerate() {
+                        for (x, cell) in row.chars().enumerate() {
+                            if cell == 'c' {
+                                placed.push(((x as i32 - 2, y as i32, z as i32 - 2), blocks.cobblestone));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        placed
+    }
+}
+
+struct BiomeProfile {
+    biome: Biome,
+    /* Point in temperature and humidity space, columns take the biome whose point is nearest to their climate */
+    climate: [f64; 2],
+    /* Added to the base height */
+    height_offset: f32,
+    /* Multiplies the generator amplitude */
+    height_scale: f32
+}
+
+const BIOMES: [BiomeProfile; 5] = [
+    BiomeProfile { biome: Biome::Ocean, climate: [0., 0.6], height_offset: -16., height_scale: 0.3 },
+    BiomeProfile { biome: Biome::Plains, climate: [0.1, 0.], height_offset: 0., height_scale: 0.4 },
+    BiomeProfile { biome: Biome::Desert, climate: [0.6, -0.4], height_offset: 2., height_scale: 0.25 },
+    BiomeProfile { biome: Biome::Mountains, climate: [-0.2, -0.6], height_offset: 20., height_scale: 2. },
+    BiomeProfile { biome: Biome::Tundra, climate: [-0.6, 0.1], height_offset: 4., height_scale: 0.6 }
+];
+
+/* Distance in climate space over which the height profiles of neighboring biomes blend together */
+const BIOME_BLEND: f64 = 0.15;
+
+/* Thresholds of the two kinds of noise caves, cheese caves are open caverns where one noise field peaks and spaghetti caves are
+   winding tunnels along the lines where two noise fields both cross zero */
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CaveSettings {
+    pub enabled: bool,
+    pub cheese_scale: f64,
+    /* Noise above this is carved, raising it makes caverns smaller and rarer */
+    pub cheese_threshold: f64,
+    pub spaghetti_scale: f64,
+    /* How close to zero both noise fields must be, raising it makes tunnels wider */
+    pub spaghetti_thickness: f64,
+    /* Caves stay this far under the surface of flooded columns so the sea doesn't hang over them */
+    pub sea_floor_margin: i32
+}
+
+impl Default for CaveSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            cheese_scale: 40.,
+            cheese_threshold: 0.32,
+            spaghetti_scale: 48.,
+            spaghetti_thickness: 0.035,
+            sea_floor_margin: 4
+        }
+    }
+}
+
+/* Veins of one ore, placed inside the stone of every chunk */
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OreSettings {
+    /* Registry name of the ore block */
+    pub block: String,
+    pub heights: Range<i32>,
+    /* Blocks in a single vein */
+    pub vein_size: u32,
+    /* Average veins per chunk, fractions give the chance of one more vein */
+    pub frequency: f32
+}
+
+impl OreSettings {
+    fn new(block: &str, heights: Range<i32>, vein_size: u32, frequency: f32) -> Self {
+        Self { block: block.to_string(), heights, vein_size, frequency }
+    }
+}
+
+/* Parameters of the height noise, saved with the world so unedited chunks regenerate identically */
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GeneratorSettings {
+    pub seed: u32,
+    pub octaves: u32,
+    /* Blocks covered by one period of the first octave */
+    pub scale: f64,
+    /* Frequency multiplier from one octave to the next */
+    pub lacunarity: f64,
+    /* Amplitude multiplier from one octave to the next */
+    pub persistence: f64,
+    pub base_height: f32,
+    /* Furthest the surface gets from the base height, before the biome scales it */
+    pub amplitude: f32,
+    /* Blocks covered by one period of the temperature and humidity noise */
+    pub biome_scale: f64,
+    /* Columns below this height are filled with water */
+    pub sea_level: i32,
+    /* Lowest height that gets generated, the world is empty below it */
+    pub min_height: i32,
+    pub overhang_scale: f64,
+    /* How many blocks the 3D noise can push the surface up or down, zero gives a plain heightfield */
+    pub overhang_strength: f32,
+    pub caves: CaveSettings,
+    pub ores: Vec<OreSettings>
+}
+
+impl Default for GeneratorSettings {
+    fn default() -> Self {
+        Self {
+            seed: 12345,
+            octaves: 4,
+            scale: 60.,
+            lacunarity: 2.,
+            persistence: 0.5,
+            base_height: 20.,
+            amplitude: 20.,
+            biome_scale: 400.,
+            sea_level: 14,
+            min_height: -48,
+            overhang_scale: 24.,
+            overhang_strength: 4.,
+            caves: CaveSettings::default(),
+            ores: vec![
+                OreSettings::new("coal_ore", -16..64, 12, 8.),
+                OreSettings::new("iron_ore", -40..24, 8, 6.),
+                OreSettings::new("gold_ore", -48..-8, 6, 2.),
+                OreSettings::new("diamond_ore", -48..-32, 4, 0.5)
+            ]
+        }
+    }
+}
 
 pub struct TerrainGenerator {
-    noise: OpenSimplex
+    settings: GeneratorSettings,
+    octaves: Vec<OpenSimplex>,
+    temperature: OpenSimplex,
+    humidity: OpenSimplex,
+    overhang: OpenSimplex,
+    cheese: OpenSimplex,
+    spaghetti: [OpenSimplex; 2]
+}
+
+/* Blocks of one column from `bottom` upwards */
+pub struct TerrainColumn {
+    pub bottom: i32,
+    pub blocks: Vec<BlockId>
 }
 
 impl TerrainGenerator {
+    pub fn new(settings: GeneratorSettings) -> Self {
+        // Each octave gets its own seed so the layers don't line up
+        let octaves = (0..settings.octaves.max(1))
+            .map(|octave| OpenSimplex::new().set_seed(settings.seed.wrapping_add(octave)))
+            .collect();
+        Self {
+            temperature: OpenSimplex::new().set_seed(settings.seed.wrapping_add(1000)),
+            humidity: OpenSimplex::new().set_seed(settings.seed.wrapping_add(2000)),
+            overhang: OpenSimplex::new().set_seed(settings.seed.wrapping_add(3000)),
+            cheese: OpenSimplex::new().set_seed(settings.seed.wrapping_add(4000)),
+            spaghetti: [
+                OpenSimplex::new().set_seed(settings.seed.wrapping_add(5000)),
+                OpenSimplex::new().set_seed(settings.seed.wrapping_add(5001))
+            ],
+            settings,
+            octaves
+        }
+    }
+
+    pub fn settings(&self) -> &GeneratorSettings {
+        &self.settings
+    }
+
+    pub fn generate_column(&self, x: i32, z: i32, blocks: &TerrainBlocks) -> TerrainColumn {
+        let height = self.get_height(x, z);
+        let biome = self.biome_at(x, z);
+        let sea_level = self.settings.sea_level;
+        let bottom = self.settings.min_height;
+        let top = (height + self.settings.overhang_strength).ceil() as i32;
+        let mut column = vec![BlockId::AIR; (top.max(sea_level) - bottom + 1).max(0) as usize];
+
+        // Walks down from the sky so every solid voxel knows how far it is below the open air above it
+        let mut depth = -1;
+        for y in (bottom..=top).rev() {
+            if !self.is_terrain(x, y, z, height) {
+                depth = -1;
+                continue;
+            }
+            depth += 1;
+            // Shores and sea beds are sand whatever the biome
+            let block = match depth {
+                0 if y <= sea_level => blocks.sand,
+                0 => biome.surface_block(blocks),
+                1..=3 if y <= sea_level => blocks.sand,
+                1..=3 => biome.subsurface_block(blocks),
+                _ => blocks.stone
+            };
+            column[(y - bottom) as usize] = block;
+        }
+
+        let caves = &self.settings.caves;
+        if caves.enabled {
+            let flooded = height.floor() as i32 <= sea_level;
+            for y in bottom..=top {
+                if flooded && y > height as i32 - caves.sea_floor_margin {
+                    break;
+                }
+                if self.is_cave(x, y, z) {
+                    column[(y - bottom) as usize] = BlockId::AIR;
+                }
+            }
+        }
+
+        for y in (bottom..sea_level).rev() {
+            let block = &mut column[(y - bottom) as usize];
+            if !block.is_air() {
+                break;
+            }
+            *block = blocks.water;
+        }
+        TerrainColumn { bottom, blocks: column }
+    }
+
+    /* Ore blocks of a chunk in chunk local positions, they only replace stone and never leave the chunk so neighbours don't
+       have to be generated first */
+    pub fn ore_veins(&self, chunk: (i32, i32), blocks: &TerrainBlocks) -> Vec<((u32, i32, u32), BlockId)> {
+        let mut veins = Vec::new();
+        for (i, (ore, block)) in self.settings.ores.iter().zip(&blocks.ores).enumerate() {
+            if ore.heights.is_empty() || block.is_air() {
+                continue;
+            }
+            let mut random = PlacementRandom::new(self.settings.seed, chunk, i as u64);
+            let count = ore.frequency.floor() as u32 + (random.next_f32() < ore.frequency.fract()) as u32;
+            for _ in 0..count {
+                let length = CHUNK_LENGTH as i32;
+                let mut position = (random.range(0..length), random.range(ore.heights.clone()), random.range(0..length));
+                for _ in 0..ore.vein_size {
+                    veins.push(((position.0 as u32, position.1, position.2 as u32), *block));
+                    // Wander one block along a random axis, staying inside the chunk and the ore's heights
+                    let step = if random.next_f32() < 0.5 { -1 } else { 1 };
+                    match random.range(0..3) {
+                        0 => position.0 = (position.0 + step).clamp(0, length - 1),
+                        1 => position.1 = (position.1 + step).clamp(ore.heights.start, ore.heights.end - 1),
+                        _ => position.2 = (position.2 + step).clamp(0, length - 1)
+                    }
+                }
+            }
+        }
+        veins
+    }
+
+    /* Decorations growing from columns of a chunk, as chunk local column positions */
+    pub fn decorations(&self, chunk: (i32, i32)) -> Vec<((u32, u32), Decoration)> {
+        const ATTEMPTS: u32 = 8;
+        let mut random = PlacementRandom::new(self.settings.seed, chunk, u64::MAX);
+        let mut decorations = Vec::new();
+        for _ in 0..ATTEMPTS {
+            let length = CHUNK_LENGTH as i32;
+            let (x, z) = (random.range(0..length), random.range(0..length));
+            let biome = self.biome_at(chunk.0 * length + x, chunk.1 * length + z);
+            if let Some(decoration) = biome.decoration(&mut random) {
+                decorations.push(((x as u32, z as u32), decoration));
+            }
+        }
+        decorations
+    }
+
+    /* Density of the terrain before caves, solid where it is positive, the 3D noise bends the surface into overhangs */
+    fn is_terrain(&self, x: i32, y: i32, z: i32, height: f32) -> bool {
+        let mut density = height.floor() - y as f32;
+        if self.settings.overhang_strength > 0. {
+            let scale = self.settings.overhang_scale;
+            density += (self.overhang.get([x as f64 / scale, y as f64 / scale, z as f64 / scale]) * 2.) as f32 * self.settings.overhang_strength;
+        }
+        density >= 0.
+    }
+
+    fn is_cave(&self, x: i32, y: i32, z: i32) -> bool {
+        let caves = &self.settings.caves;
+        let cheese_point = [x as f64 / caves.cheese_scale, y as f64 / caves.cheese_scale, z as f64 / caves.cheese_scale];
+        if self.cheese.get(cheese_point) > caves.cheese_threshold {
+            return true;
+        }
+        // Squashing the vertical axis keeps tunnels from turning into deep shafts
+        let spaghetti_point = [x as f64 / caves.spaghetti_scale, y as f64 * 1.5 / caves.spaghetti_scale, z as f64 / caves.spaghetti_scale];
+        self.spaghetti.iter().all(|noise| noise.get(spaghetti_point).abs() < caves.spaghetti_thickness)
+    }
+
+    /* Height profiles of every biome weighted by how close the column's climate is to each, so borders slope smoothly */
     pub fn get_height(&self, x: i32, z: i32) -> f32 {
-        let noise_x = (x as f64) / 20.;
-        let noise_z = (z as f64) / 20.;
-        let noise_height = self.noise.get([noise_x, noise_z]) as f32;
-        return (noise_height + 1.) * 20.;
+        let noise = self.fractal_noise(x as f64, z as f64) as f32;
+        let climate = self.climate(x, z);
+        let mut height = 0.;
+        let mut total_weight = 0.;
+        for profile in &BIOMES {
+            let weight = (-Self::climate_distance(climate, profile) / (BIOME_BLEND * BIOME_BLEND)).exp() as f32;
+            height += (profile.height_offset + noise * self.settings.amplitude * profile.height_scale) * weight;
+            total_weight += weight;
+        }
+        self.settings.base_height + height / total_weight
+    }
+
+    pub fn biome_at(&self, x: i32, z: i32) -> Biome {
+        let climate = self.climate(x, z);
+        BIOMES.iter()
+            .min_by(|a, b| Self::climate_distance(climate, a).total_cmp(&Self::climate_distance(climate, b)))
+            .unwrap()
+            .biome
+    }
+
+    /* Temperature and humidity of a column, stretched to roughly -1 to 1 */
+    fn climate(&self, x: i32, z: i32) -> [f64; 2] {
+        let point = [x as f64 / self.settings.biome_scale, z as f64 / self.settings.biome_scale];
+        [(self.temperature.get(point) * 2.).clamp(-1., 1.), (self.humidity.get(point) * 2.).clamp(-1., 1.)]
+    }
+
+    /* Squared distance between a climate and a biome's climate point */
+    fn climate_distance(climate: [f64; 2], profile: &BiomeProfile) -> f64 {
+        (climate[0] - profile.climate[0]).powi(2) + (climate[1] - profile.climate[1]).powi(2)
+    }
+
+    /* Sum of the octaves normalised back into -1 to 1 */
+    fn fractal_noise(&self, x: f64, z: f64) -> f64 {
+        let mut frequency = 1. / self.settings.scale;
+        let mut amplitude = 1.;
+        let mut total = 0.;
+        let mut total_amplitude = 0.;
+        for octave in &self.octaves {
+            total += octave.get([x * frequency, z * frequency]) * amplitude;
+            total_amplitude += amplitude;
+            frequency *= self.settings.lacunarity;
+            amplitude *= self.settings.persistence;
+        }
+        total / total_amplitude
     }
 }
 
 impl Default for TerrainGenerator {
     fn default() -> Self {
-        Self {
-            noise: OpenSimplex::new().set_seed(12345)
+        Self::new(GeneratorSettings::default())
+    }
+}
+
+/* Deterministic random numbers for placement, seeded from the world seed and a chunk position so regenerating a chunk
+   places everything again in the same spots */
+struct PlacementRandom(u64);
+
+impl PlacementRandom {
+    fn new(seed: u32, chunk: (i32, i32), salt: u64) -> Self {
+        let state = [chunk.0 as u32 as u64, chunk.1 as u32 as u64, salt]
+            .iter()
+            .fold(seed as u64, |state, value| Self::mix(state ^ value));
+        Self(state)
+    }
+
+    /* SplitMix64 */
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        Self::mix(self.0)
+    }
+
+    fn mix(value: u64) -> u64 {
+        let value = (value ^ (value >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        let value = (value ^ (value >> 27)).wrapping_mul(0x94d049bb133111eb);
+        value ^ (value >> 31)
+    }
+
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    fn range(&mut self, range: Range<i32>) -> i32 {
+        range.start + (self.next_u64() % (range.end - range.start) as u64) as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_generates_same_heights() {
+        let a = TerrainGenerator::new(GeneratorSettings::default());
+        let b = TerrainGenerator::new(GeneratorSettings::default());
+        let c = TerrainGenerator::new(GeneratorSettings { seed: 54321, ..default() });
+        let heights = |generator: &TerrainGenerator| (0..64).map(|i| generator.get_height(i * 7, -i * 3)).collect::<Vec<f32>>();
+
+        assert_eq!(heights(&a), heights(&b));
+        assert_ne!(heights(&a), heights(&c));
+    }
+
+    #[test]
+    fn heights_stay_within_biome_profiles() {
+        let settings = GeneratorSettings { octaves: 6, base_height: 10., amplitude: 5., ..default() };
+        let generator = TerrainGenerator::new(settings);
+        let lowest = BIOMES.iter().map(|profile| profile.height_offset - 5. * profile.height_scale).fold(f32::MAX, f32::min);
+        let highest = BIOMES.iter().map(|profile| profile.height_offset + 5. * profile.height_scale).fold(f32::MIN, f32::max);
+        for x in -100..100 {
+            let height = generator.get_height(x * 13, x * 5);
+            assert!((10. + lowest..=10. + highest).contains(&height));
+        }
+    }
+
+    fn flat_settings() -> GeneratorSettings {
+        GeneratorSettings { overhang_strength: 0., caves: CaveSettings { enabled: false, ..default() }, ..default() }
+    }
+
+    fn test_blocks() -> TerrainBlocks {
+        let ores = (7..7 + GeneratorSettings::default().ores.len() as u16).map(BlockId).collect();
+        TerrainBlocks {
+            grass: BlockId(1), dirt: BlockId(2), stone: BlockId(3), sand: BlockId(4), snow: BlockId(5), water: BlockId(6),
+            log: BlockId(11), leaves: BlockId(12), cobblestone: BlockId(13), ores
+        }
+    }
+
+    #[test]
+    fn decorations_are_deterministic_and_stay_within_reach() {
+        let generator = TerrainGenerator::default();
+        let blocks = test_blocks();
+        let decorations: Vec<_> = (0..64).flat_map(|x| generator.decorations((x, -x))).collect();
+        assert!(decorations.iter().any(|(_, decoration)| matches!(decoration, Decoration::Tree { .. })));
+        assert_eq!(generator.decorations((5, -5)), generator.decorations((5, -5)));
+
+        for (_, decoration) in decorations {
+            for ((x, _, z), _) in decoration.blocks(&blocks) {
+                assert!(x.abs() <= Decoration::MAX_REACH && z.abs() <= Decoration::MAX_REACH);
+            }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn ore_veins_are_deterministic_and_stay_in_range() {
+        let generator = TerrainGenerator::default();
+        let blocks = test_blocks();
+        let settings = generator.settings();
+        for chunk in [(0, 0), (-3, 7), (12, -40)] {
+            let veins = generator.ore_veins(chunk, &blocks);
+            assert_eq!(veins, generator.ore_veins(chunk, &blocks));
+            for ((x, y, z), block) in veins {
+                let ore = blocks.ores.iter().position(|ore| *ore == block).unwrap();
+                assert!(settings.ores[ore].heights.contains(&y));
+                assert!(x < CHUNK_LENGTH as u32 && z < CHUNK_LENGTH as u32);
+            }
+        }
+        assert_ne!(generator.ore_veins((0, 0), &blocks), generator.ore_veins((1, 0), &blocks));
+    }
+
+    #[test]
+    fn cave_free_columns_are_solid_to_the_bottom() {
+        let generator = TerrainGenerator::new(flat_settings());
+        let blocks = test_blocks();
+        for x in 0..32 {
+            let column = generator.generate_column(x * 31, 11, &blocks);
+            let height = generator.get_height(x * 31, 11).floor() as i32;
+            let surface = (height - column.bottom) as usize;
+
+            assert!(column.blocks[..=surface].iter().all(|block| !block.is_air() && *block != blocks.water));
+            assert!(column.blocks[..surface - 3].iter().all(|block| *block == blocks.stone));
+            assert!(column.blocks[surface + 1..].iter().all(|block| block.is_air() || *block == blocks.water));
+        }
+    }
+
+    #[test]
+    fn caves_carve_underground() {
+        let blocks = test_blocks();
+        let count_air = |generator: &TerrainGenerator| -> usize {
+            (0..64).map(|x| {
+                let column = generator.generate_column(x * 5, x * 3, &blocks);
+                let height = generator.get_height(x * 5, x * 3).floor() as i32;
+                column.blocks[..(height - column.bottom - 8).max(0) as usize].iter().filter(|block| block.is_air()).count()
+            }).sum()
+        };
+        assert_eq!(count_air(&TerrainGenerator::new(flat_settings())), 0);
+        assert!(count_air(&TerrainGenerator::default()) > 0);
+    }
+
+    #[test]
+    fn every_biome_appears() {
+        let generator = TerrainGenerator::default();
+        let mut found = Vec::new();
+        for x in -60..60 {
+            for z in -60..60 {
+                let biome = generator.biome_at(x * 64, z * 64);
+                if !found.contains(&biome) {
+                    found.push(biome);
+                }
+            }
+        }
+        assert_eq!(found.len(), BIOMES.len());
+    }
+
+    #[test]
+    fn heights_blend_across_biome_borders() {
+        let generator = TerrainGenerator::default();
+        // Neighboring columns never jump by more than the steepest biome could on its own
+        for x in -2000..2000 {
+            let step = (generator.get_height(x, 37) - generator.get_height(x + 1, 37)).abs();
+            assert!(step < 4., "height jumps by {} at x {}", step, x);
+        }
+    }
+}
@@ -0,0 +1,511 @@
+use std::collections::VecDeque;
+use bevy::utils::{HashMap, HashSet};
+use crate::game::world::block::BlockRegistry;
+use crate::game::world::chunk::{Chunk, CHUNK_LENGTH, CHUNK_VOLUME};
+use crate::game::world::world::{split_voxel_position, touched_sections};
+
+pub const MAX_LIGHT: u8 = 15;
+/* Skylight followed by the red, green and blue block light */
+pub const LIGHT_CHANNELS: usize = 4;
+const SKY_CHANNEL: usize = 0;
+const BLOCK_CHANNELS: [usize; 3] = [1, 2, 3];
+
+const DIRECTIONS: [(i32, i32, i32); 6] = [(1, 0, 0), (-1, 0, 0), (0, 1, 0), (0, -1, 0), (0, 0, 1), (0, 0, -1)];
+const DOWN: (i32, i32, i32) = (0, -1, 0);
+
+/* Light reaching a voxel as one nibble per channel from 0 (dark) to 15, skylight in the highest nibble followed by red, green
+   and blue block light. Each colour of block light spreads on its own so overlapping lights mix */
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Light(u16);
+
+impl Light {
+    pub const DARK: Light = Light(0);
+    /* Open sky, full skylight and no block light */
+    pub const SKY: Light = Light((MAX_LIGHT as u16) << 12);
+
+    /* Block light of an emitter, its level split between the channels by the colour */
+    pub fn emitted(level: u8, color: [f32; 3]) -> Self {
+        BLOCK_CHANNELS.iter().zip(color).fold(Light::DARK, |light, (channel, intensity)| {
+            light.with_channel(*channel, (level as f32 * intensity.clamp(0., 1.)).round() as u8)
+        })
+    }
+
+    pub fn sky(self) -> u8 {
+        self.channel(SKY_CHANNEL)
+    }
+
+    pub fn block(self) -> [u8; 3] {
+        BLOCK_CHANNELS.map(|channel| self.channel(channel))
+    }
+
+    /* Brightest of the two in every channel */
+    pub fn max(self, other: Light) -> Self {
+        (0..LIGHT_CHANNELS).fold(self, |light, channel| light.with_channel(channel, self.channel(channel).max(other.channel(channel))))
+    }
+
+    /* Rounded average of every channel, dark when there is nothing to average */
+    pub fn average(lights: &[Light]) -> Self {
+        if lights.is_empty() {
+            return Light::DARK;
+        }
+        let count = lights.len() as u32;
+        (0..LIGHT_CHANNELS).fold(Light::DARK, |average, channel| {
+            let sum: u32 = lights.iter().map(|light| light.channel(channel) as u32).sum();
+            average.with_channel(channel, ((sum + count / 2) / count) as u8)
+        })
+    }
+
+    fn channel(self, channel: usize) -> u8 {
+        (self.0 >> Self::shift(channel)) as u8 & MAX_LIGHT
+    }
+
+    fn with_channel(self, channel: usize, level: u8) -> Self {
+        let shift = Self::shift(channel);
+        Self(self.0 & !((MAX_LIGHT as u16) << shift) | (level as u16) << shift)
+    }
+
+    fn shift(channel: usize) -> usize {
+        (LIGHT_CHANNELS - 1 - channel) * 4
+    }
+}
+
+/* Light of every voxel in a section, sections lit the same everywhere such as open sky or solid rock keep a single value */
+#[derive(Clone)]
+pub enum LightStorage {
+    Uniform(Light),
+    Full(Vec<Light>)
+}
+
+impl LightStorage {
+    pub fn new(light: Light) -> Self {
+        LightStorage::Uniform(light)
+    }
+
+    pub fn get(&self, index: usize) -> Light {
+        match self {
+            LightStorage::Uniform(light) => *light,
+            LightStorage::Full(lights) => lights[index]
+        }
+    }
+
+    pub fn set(&mut self, index: usize, light: Light) {
+        if let LightStorage::Uniform(current) = self {
+            if *current == light {
+                return;
+            }
+            *self = LightStorage::Full(vec![*current; CHUNK_VOLUME]);
+        }
+        if let LightStorage::Full(lights) = self {
+            lights[index] = light;
+        }
+    }
+
+    pub fn optimize(&mut self) {
+        if let LightStorage::Full(lights) = self {
+            if lights.iter().all(|light| *light == lights[0]) {
+                *self = LightStorage::Uniform(lights[0]);
+            }
+        }
+    }
+
+    pub fn heap_size(&self) -> usize {
+        match self {
+            LightStorage::Uniform(_) => 0,
+            LightStorage::Full(lights) => lights.capacity() * std::mem::size_of::<Light>()
+        }
+    }
+}
+
+/* Flood fills light through the loaded chunks, light stops at unloaded chunks and flows in from their borders once they load.
+   Remembers the sections whose light changed so they can be remeshed */
+pub struct LightEngine<'a> {
+    chunks: &'a mut HashMap<(i32, i32), Chunk>,
+    blocks: &'a BlockRegistry,
+    changed: HashSet<(i32, i32, i32)>
+}
+
+impl<'a> LightEngine<'a> {
+    pub fn new(chunks: &'a mut HashMap<(i32, i32), Chunk>, blocks: &'a BlockRegistry) -> Self {
+        Self { chunks, blocks, changed: HashSet::default() }
+    }
+
+    pub fn into_changed(self) -> HashSet<(i32, i32, i32)> {
+        self.changed
+    }
+
+    /* Lights a newly loaded chunk, sunlight falls straight down every column until it reaches a block that isn't clear and is
+       then flood filled sideways from the sunlit columns, down through translucent blocks, out of the chunk's emitters and
+       across the borders with loaded neighbors */
+    pub fn light_chunk(&mut self, position: (i32, i32)) {
+        let length = CHUNK_LENGTH as i32;
+        let origin = (position.0 * length, position.1 * length);
+        let chunk = match self.chunks.get_mut(&position) {
+            Some(chunk) => chunk,
+            None => return
+        };
+        chunk.clear_light();
+        let range = chunk.section_range();
+        let (bottom, top) = (range.start * length, range.end * length);
+
+        // Lowest sunlit height of every column, and the columns whose sunlight goes on dimmed through a translucent block
+        let mut sky_floor = [[top; CHUNK_LENGTH]; CHUNK_LENGTH];
+        let mut dimmed_columns = Vec::new();
+        for (x, floors) in sky_floor.iter_mut().enumerate() {
+            for (z, floor) in floors.iter_mut().enumerate() {
+                for y in (bottom..top).rev() {
+                    let block = chunk.get_voxel((x as u32, y, z as u32)).block;
+                    if self.blocks.is_translucent(block) {
+                        dimmed_columns.push((x as i32, z as i32));
+                    }
+                    if self.blocks.is_opaque(block) || self.blocks.is_translucent(block) {
+                        break;
+                    }
+                    chunk.set_light((x as u32, y, z as u32), Light::SKY);
+                    *floor = y;
+                }
+            }
+        }
+        chunk.optimize_light();
+
+        let mut queues: [VecDeque<(i32, i32, i32)>; LIGHT_CHANNELS] = Default::default();
+        // Sunlit voxels next to a deeper column light it from the side
+        for x in 0..length {
+            for z in 0..length {
+                let shaded_below = [(1, 0), (-1, 0), (0, 1), (0, -1)].iter()
+                    .filter(|(dx, dz)| (0..length).contains(&(x + dx)) && (0..length).contains(&(z + dz)))
+                    .map(|(dx, dz)| sky_floor[(x + dx) as usize][(z + dz) as usize])
+                    .max()
+                    .unwrap_or(top);
+                for y in sky_floor[x as usize][z as usize]..shaded_below {
+                    queues[SKY_CHANNEL].push_back((origin.0 + x, y, origin.1 + z));
+                }
+            }
+        }
+        for (x, z) in dimmed_columns {
+            queues[SKY_CHANNEL].push_back((origin.0 + x, sky_floor[x as usize][z as usize], origin.1 + z));
+        }
+        let chunk = &self.chunks[&position];
+        let emitters = chunk.emitters(self.blocks);
+        for (local_position, emission) in emitters {
+            let emitter = (origin.0 + local_position.0 as i32, local_position.1, origin.1 + local_position.2 as i32);
+            self.set_light(emitter, self.light(emitter).unwrap().max(emission));
+            for channel in BLOCK_CHANNELS {
+                queues[channel].push_back(emitter);
+            }
+        }
+        // Light on either side of a border with a loaded neighbor flows across it
+        for (dx, dz) in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+            let neighbor = match self.chunks.get(&(position.0 + dx, position.1 + dz)) {
+                Some(neighbor) => neighbor,
+                None => continue
+            };
+            let neighbor_range = neighbor.section_range();
+            let heights = bottom.min(neighbor_range.start * length)..top.max(neighbor_range.end * length);
+            for i in 0..length {
+                let (inside, outside) = match (dx, dz) {
+                    (1, _) => ((length - 1, i), (length, i)),
+                    (-1, _) => ((0, i), (-1, i)),
+                    (_, 1) => ((i, length - 1), (i, length)),
+                    _ => ((i, 0), (i, -1))
+                };
+                for y in heights.clone() {
+                    for queue in queues.iter_mut() {
+                        queue.push_back((origin.0 + inside.0, y, origin.1 + inside.1));
+                        queue.push_back((origin.0 + outside.0, y, origin.1 + outside.1));
+                    }
+                }
+            }
+        }
+        for (channel, queue) in queues.into_iter().enumerate() {
+            self.propagate(channel, queue);
+        }
+    }
+
+    /* Relights around a voxel whose block changed, light it used to pass on is removed before the surrounding light and its own
+       emission flood back in */
+    pub fn update_voxel(&mut self, position: (i32, i32, i32)) {
+        let light = match self.light(position) {
+            Some(light) => light,
+            None => return
+        };
+        let opaque = self.is_opaque(position);
+        let translucent = self.is_translucent(position);
+        let emission = self.emission(position);
+        for channel in 0..LIGHT_CHANNELS {
+            let level = light.channel(channel);
+            let emitted = emission.channel(channel);
+            let mut relight = VecDeque::new();
+            // Block light brighter than the new block emits may have come from an emitter that was just replaced, and a
+            // translucent block dims whatever used to shine through the voxel
+            if opaque || translucent || channel != SKY_CHANNEL && level > emitted {
+                self.set_channel(position, channel, 0);
+                relight = self.remove(channel, VecDeque::from([(position, level)]));
+            }
+            if !opaque {
+                relight.extend(DIRECTIONS.iter().map(|direction| offset(position, *direction)));
+            }
+            if emitted > self.light(position).unwrap().channel(channel) {
+                self.set_channel(position, channel, emitted);
+                relight.push_back(position);
+            }
+            self.propagate(channel, relight);
+        }
+    }
+
+    fn propagate(&mut self, channel: usize, mut queue: VecDeque<(i32, i32, i32)>) {
+        while let Some(position) = queue.pop_front() {
+            let level = match self.light(position) {
+                Some(light) => light.channel(channel),
+                None => continue
+            };
+            for direction in DIRECTIONS {
+                let neighbor = offset(position, direction);
+                let spread = spread_level(channel, level, direction, self.is_translucent(neighbor));
+                if spread == 0 || self.is_opaque(neighbor) {
+                    continue;
+                }
+                if let Some(light) = self.light(neighbor) {
+                    if spread > light.channel(channel) {
+                        self.set_light(neighbor, light.with_channel(channel, spread));
+                        queue.push_back(neighbor);
+                    }
+                }
+            }
+        }
+    }
+
+    /* Darkens everything lit by the queued voxels, returning the brighter voxels around the darkened area that light it back up */
+    fn remove(&mut self, channel: usize, mut queue: VecDeque<((i32, i32, i32), u8)>) -> VecDeque<(i32, i32, i32)> {
+        let mut relight = VecDeque::new();
+        while let Some((position, level)) = queue.pop_front() {
+            for direction in DIRECTIONS {
+                let neighbor = offset(position, direction);
+                let light = match self.light(neighbor) {
+                    Some(light) => light,
+                    None => continue
+                };
+                let neighbor_level = light.channel(channel);
+                if neighbor_level == 0 {
+                    continue;
+                }
+                if neighbor_level < level || spread_level(channel, level, direction, self.is_translucent(neighbor)) == neighbor_level && neighbor_level == MAX_LIGHT {
+                    self.set_channel(neighbor, channel, 0);
+                    queue.push_back((neighbor, neighbor_level));
+                    // Emitters in the darkened area shine again once it has been cleared
+                    let emitted = self.emission(neighbor).channel(channel);
+                    if emitted > 0 {
+                        self.set_channel(neighbor, channel, emitted);
+                        relight.push_back(neighbor);
+                    }
+                } else {
+                    relight.push_back(neighbor);
+                }
+            }
+        }
+        relight
+    }
+
+    /* None when the chunk isn't loaded */
+    fn light(&self, position: (i32, i32, i32)) -> Option<Light> {
+        let (chunk_position, local_position) = split_voxel_position(position);
+        Some(self.chunks.get(&chunk_position)?.get_light(local_position))
+    }
+
+    fn set_light(&mut self, position: (i32, i32, i32), light: Light) {
+        let (chunk_position, local_position) = split_voxel_position(position);
+        if let Some(chunk) = self.chunks.get_mut(&chunk_position) {
+            chunk.set_light(local_position, light);
+            self.changed.extend(touched_sections(position));
+        }
+    }
+
+    fn set_channel(&mut self, position: (i32, i32, i32), channel: usize, level: u8) {
+        if let Some(light) = self.light(position) {
+            self.set_light(position, light.with_channel(channel, level));
+        }
+    }
+
+    fn emission(&self, position: (i32, i32, i32)) -> Light {
+        let (chunk_position, local_position) = split_voxel_position(position);
+        match self.chunks.get(&chunk_position) {
+            Some(chunk) => self.blocks.emission(chunk.get_voxel(local_position).block),
+            None => Light::DARK
+        }
+    }
+
+    /* Unloaded chunks and everything below a column's lowest section block light */
+    fn is_opaque(&self, position: (i32, i32, i32)) -> bool {
+        let (chunk_position, local_position) = split_voxel_position(position);
+        match self.chunks.get(&chunk_position) {
+            Some(chunk) if position.1 >= chunk.section_range().start * CHUNK_LENGTH as i32 => {
+                self.blocks.is_opaque(chunk.get_voxel(local_position).block)
+            }
+            _ => true
+        }
+    }
+
+    fn is_translucent(&self, position: (i32, i32, i32)) -> bool {
+        let (chunk_position, local_position) = split_voxel_position(position);
+        match self.chunks.get(&chunk_position) {
+            Some(chunk) => self.blocks.is_translucent(chunk.get_voxel(local_position).block),
+            None => false
+        }
+    }
+}
+
+/* Light passes on one level dimmer, except full sunlight which keeps shining straight down. Translucent blocks take away
+   another level */
+fn spread_level(channel: usize, level: u8, direction: (i32, i32, i32), translucent: bool) -> u8 {
+    let level = if channel == SKY_CHANNEL && direction == DOWN && level == MAX_LIGHT {
+        MAX_LIGHT
+    } else {
+        level.saturating_sub(1)
+    };
+    level.saturating_sub(translucent as u8)
+}
+
+fn offset(position: (i32, i32, i32), direction: (i32, i32, i32)) -> (i32, i32, i32) {
+    (position.0 + direction.0, position.1 + direction.1, position.2 + direction.2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::world::block::BlockId;
+    use crate::game::world::block::test_blocks::{self, LAMP, SLAB, STONE, WATER};
+
+    /* A chunk with a stone floor at height 0 and a roof at height 8 over x below 8 */
+    fn roofed_chunks() -> HashMap<(i32, i32), Chunk> {
+        let mut chunk = Chunk::new((0, 0));
+        for x in 0..CHUNK_LENGTH as u32 {
+            for z in 0..CHUNK_LENGTH as u32 {
+                chunk.set_voxel((x, 0, z), STONE);
+                if x < 8 {
+                    chunk.set_voxel((x, 8, z), STONE);
+                }
+            }
+        }
+        let mut chunks = HashMap::default();
+        chunks.insert((0, 0), chunk);
+        chunks
+    }
+
+    fn sky(chunks: &HashMap<(i32, i32), Chunk>, position: (u32, i32, u32)) -> u8 {
+        chunks[&(0, 0)].get_light(position).sky()
+    }
+
+    #[test]
+    fn sunlight_falls_and_spreads_under_overhangs() {
+        let blocks = test_blocks::registry();
+        let mut chunks = roofed_chunks();
+        LightEngine::new(&mut chunks, &blocks).light_chunk((0, 0));
+
+        assert_eq!(sky(&chunks, (12, 1, 4)), MAX_LIGHT);
+        assert_eq!(sky(&chunks, (7, 1, 4)), MAX_LIGHT - 1);
+        assert_eq!(sky(&chunks, (3, 1, 4)), MAX_LIGHT - 5);
+        assert_eq!(sky(&chunks, (3, 8, 4)), 0);
+        assert_eq!(sky(&chunks, (3, 20, 4)), MAX_LIGHT);
+    }
+
+    #[test]
+    fn shaped_blocks_let_light_through() {
+        let blocks = test_blocks::registry();
+        let mut chunks = roofed_chunks();
+        chunks.get_mut(&(0, 0)).unwrap().set_voxel((3, 8, 4), SLAB);
+        LightEngine::new(&mut chunks, &blocks).light_chunk((0, 0));
+
+        assert_eq!(sky(&chunks, (3, 8, 4)), MAX_LIGHT);
+        assert_eq!(sky(&chunks, (3, 1, 4)), MAX_LIGHT);
+        assert_eq!(sky(&chunks, (2, 1, 4)), MAX_LIGHT - 1);
+    }
+
+    #[test]
+    fn edits_remove_and_restore_light() {
+        let blocks = test_blocks::registry();
+        let mut chunks = roofed_chunks();
+        LightEngine::new(&mut chunks, &blocks).light_chunk((0, 0));
+
+        // Closing off the gap between roof and floor at x 8 shades everything under the roof
+        for y in 1..=8 {
+            for z in 0..CHUNK_LENGTH as u32 {
+                chunks.get_mut(&(0, 0)).unwrap().set_voxel((8, y, z), STONE);
+            }
+        }
+        let mut engine = LightEngine::new(&mut chunks, &blocks);
+        for y in 1..=8 {
+            for z in 0..CHUNK_LENGTH as i32 {
+                engine.update_voxel((8, y, z));
+            }
+        }
+        assert!(engine.into_changed().contains(&(0, 0, 0)));
+        assert_eq!(sky(&chunks, (3, 1, 4)), 0);
+        assert_eq!(sky(&chunks, (12, 1, 4)), MAX_LIGHT);
+
+        chunks.get_mut(&(0, 0)).unwrap().set_voxel((8, 1, 4), BlockId::AIR);
+        LightEngine::new(&mut chunks, &blocks).update_voxel((8, 1, 4));
+        assert_eq!(sky(&chunks, (8, 1, 4)), MAX_LIGHT - 1);
+        assert_eq!(sky(&chunks, (3, 1, 4)), MAX_LIGHT - 6);
+    }
+
+    #[test]
+    fn emitters_spread_coloured_light_until_removed() {
+        let blocks = test_blocks::registry();
+        let mut chunks = roofed_chunks();
+        chunks.get_mut(&(0, 0)).unwrap().set_voxel((2, 4, 4), LAMP);
+        LightEngine::new(&mut chunks, &blocks).light_chunk((0, 0));
+        let block = |chunks: &HashMap<(i32, i32), Chunk>, position| chunks[&(0, 0)].get_light(position).block();
+
+        assert_eq!(block(&chunks, (2, 4, 4)), [12, 6, 0]);
+        assert_eq!(block(&chunks, (2, 4, 7)), [9, 3, 0]);
+        assert_eq!(block(&chunks, (2, 4, 12)), [4, 0, 0]);
+
+        chunks.get_mut(&(0, 0)).unwrap().set_voxel((2, 4, 4), BlockId::AIR);
+        LightEngine::new(&mut chunks, &blocks).update_voxel((2, 4, 4));
+        assert_eq!(block(&chunks, (2, 4, 7)), [0, 0, 0]);
+
+        chunks.get_mut(&(0, 0)).unwrap().set_voxel((2, 4, 10), LAMP);
+        LightEngine::new(&mut chunks, &blocks).update_voxel((2, 4, 10));
+        assert_eq!(block(&chunks, (2, 4, 7)), [9, 3, 0]);
+        assert_eq!(block(&chunks, (2, 4, 4)), [6, 0, 0]);
+    }
+
+    #[test]
+    fn emitters_at_the_top_dont_grow_the_column() {
+        let blocks = test_blocks::registry();
+        let mut chunks = roofed_chunks();
+        chunks.get_mut(&(0, 0)).unwrap().set_voxel((12, 15, 4), LAMP);
+        let range = chunks[&(0, 0)].section_range();
+        LightEngine::new(&mut chunks, &blocks).light_chunk((0, 0));
+
+        assert_eq!(chunks[&(0, 0)].section_range(), range);
+        assert_eq!(chunks[&(0, 0)].get_light((12, 14, 4)).block(), [11, 5, 0]);
+        assert_eq!(chunks[&(0, 0)].get_light((12, 16, 4)), Light::SKY);
+    }
+
+    #[test]
+    fn translucent_blocks_dim_light() {
+        let blocks = test_blocks::registry();
+        let mut chunks = roofed_chunks();
+        for x in 8..CHUNK_LENGTH as u32 {
+            for z in 0..CHUNK_LENGTH as u32 {
+                for y in 1..=4 {
+                    chunks.get_mut(&(0, 0)).unwrap().set_voxel((x, y, z), WATER);
+                }
+            }
+        }
+        LightEngine::new(&mut chunks, &blocks).light_chunk((0, 0));
+        assert_eq!(sky(&chunks, (12, 5, 4)), MAX_LIGHT);
+        assert_eq!(sky(&chunks, (12, 4, 4)), MAX_LIGHT - 1);
+        assert_eq!(sky(&chunks, (12, 1, 4)), MAX_LIGHT - 7);
+
+        chunks.get_mut(&(0, 0)).unwrap().set_voxel((12, 4, 4), BlockId::AIR);
+        LightEngine::new(&mut chunks, &blocks).update_voxel((12, 4, 4));
+        assert_eq!(sky(&chunks, (12, 4, 4)), MAX_LIGHT);
+        assert_eq!(sky(&chunks, (12, 3, 4)), MAX_LIGHT - 1);
+
+        chunks.get_mut(&(0, 0)).unwrap().set_voxel((12, 4, 4), WATER);
+        LightEngine::new(&mut chunks, &blocks).update_voxel((12, 4, 4));
+        assert_eq!(sky(&chunks, (12, 4, 4)), MAX_LIGHT - 1);
+        assert_eq!(sky(&chunks, (12, 3, 4)), MAX_LIGHT - 3);
+    }
+}
@@ -0,0 +1,119 @@
+use bevy::ecs::system::lifetimeless::SRes;
+use bevy::ecs::system::SystemParamItem;
+use bevy::pbr::MaterialPipeline;
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::render::mesh::{MeshVertexAttribute, MeshVertexBufferLayout};
+use bevy::render::render_asset::{PrepareAssetError, RenderAsset, RenderAssets};
+use bevy::render::render_resource::{
+    BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry,
+    BindingResource, BindingType, RenderPipelineDescriptor, SamplerBindingType, ShaderStages, SpecializedMeshPipelineError,
+    TextureSampleType, TextureViewDimension, VertexFormat
+};
+use bevy::render::renderer::RenderDevice;
+
+/* Atlas rectangle the uvs of a vertex repeat inside, lets greedy quads tile their texture across the whole quad */
+pub const ATTRIBUTE_ATLAS_TILE: MeshVertexAttribute = MeshVertexAttribute::new("AtlasTile", 581234960, VertexFormat::Float32x4);
+
+const SHADER_PATH: &str = "shaders/voxel.wgsl";
+
+/* Terrain material sampling the block texture atlas, lit by the scene's ambient and directional lights. Blended materials draw
+   the see-through blocks over the rest of the terrain */
+#[derive(Debug, Clone, TypeUuid)]
+#[uuid = "3f0c7b8e-5d2a-4c61-9a7e-2b8d4e6f1a93"]
+pub struct VoxelMaterial {
+    pub atlas: Handle<Image>,
+    pub alpha_mode: AlphaMode
+}
+
+pub struct GpuVoxelMaterial {
+    bind_group: BindGroup,
+    alpha_mode: AlphaMode
+}
+
+impl RenderAsset for VoxelMaterial {
+    type ExtractedAsset = VoxelMaterial;
+    type PreparedAsset = GpuVoxelMaterial;
+    type Param = (SRes<RenderDevice>, SRes<MaterialPipeline<Self>>, SRes<RenderAssets<Image>>);
+
+    fn extract_asset(&self) -> Self::ExtractedAsset {
+        self.clone()
+    }
+
+    fn prepare_asset(
+        material: Self::ExtractedAsset,
+        (render_device, material_pipeline, images): &mut SystemParamItem<Self::Param>
+    ) -> Result<Self::PreparedAsset, PrepareAssetError<Self::ExtractedAsset>> {
+        let atlas = match images.get(&material.atlas) {
+            Some(atlas) => atlas,
+            None => return Err(PrepareAssetError::RetryNextUpdate(material))
+        };
+        let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+            entries: &[
+                BindGroupEntry { binding: 0, resource: BindingResource::TextureView(&atlas.texture_view) },
+                BindGroupEntry { binding: 1, resource: BindingResource::Sampler(&atlas.sampler) }
+            ],
+            label: Some("voxel_material_bind_group"),
+            layout: &material_pipeline.material_layout
+        });
+        Ok(GpuVoxelMaterial { bind_group, alpha_mode: material.alpha_mode })
+    }
+}
+
+impl Material for VoxelMaterial {
+    fn vertex_shader(asset_server: &AssetServer) -> Option<Handle<Shader>> {
+        Some(asset_server.load(SHADER_PATH))
+    }
+
+    fn fragment_shader(asset_server: &AssetServer) -> Option<Handle<Shader>> {
+        Some(asset_server.load(SHADER_PATH))
+    }
+
+    fn bind_group(material: &<Self as RenderAsset>::PreparedAsset) -> &BindGroup {
+        &material.bind_group
+    }
+
+    fn alpha_mode(material: &<Self as RenderAsset>::PreparedAsset) -> AlphaMode {
+        material.alpha_mode
+    }
+
+    fn bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+        render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        multisampled: false,
+                        sample_type: TextureSampleType::Float { filterable: true },
+                        view_dimension: TextureViewDimension::D2
+                    },
+                    count: None
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                    count: None
+                }
+            ],
+            label: Some("voxel_material_layout")
+        })
+    }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayout
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        let vertex_layout = layout.get_layout(&[
+            Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
+            Mesh::ATTRIBUTE_NORMAL.at_shader_location(1),
+            Mesh::ATTRIBUTE_UV_0.at_shader_location(2),
+            ATTRIBUTE_ATLAS_TILE.at_shader_location(3),
+            Mesh::ATTRIBUTE_COLOR.at_shader_location(4)
+        ])?;
+        descriptor.vertex.buffers = vec![vertex_layout];
+        Ok(())
+    }
+}
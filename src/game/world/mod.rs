@@ -1,5 +1,14 @@
 pub mod terrain;
+pub mod atlas;
+pub mod block;
 mod chunk;
 pub mod voxel;
 pub mod world;
-mod generator;
\ No newline at end of file
+mod generator;
+pub mod material;
+mod palette;
+mod region;
+mod light;
+pub mod model;
+pub mod time;
+pub mod raycast;
\ No newline at end of file
@@ -0,0 +1,164 @@
+use serde::Deserialize;
+use crate::game::world::block::{BlockId, BlockRegistry};
+
+/* Shape a block is drawn and collides with inside its voxel */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+pub enum BlockModel {
+    #[default]
+    Cube,
+    /* Two crossed quads like grass and flowers, nothing to stand on */
+    Cross,
+    /* Bottom half of a cube */
+    Slab,
+    /* A slab with a half height step on the side it faces */
+    Stairs(Facing),
+    /* A post that reaches out to neighboring fences and full blocks */
+    Fence
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub enum Facing {
+    North,
+    South,
+    East,
+    West
+}
+
+impl Facing {
+    pub const ALL: [Facing; 4] = [Facing::North, Facing::South, Facing::East, Facing::West];
+
+    /* Facing closest to a horizontal direction */
+    pub fn from_direction(x: f32, z: f32) -> Facing {
+        if x.abs() > z.abs() {
+            if x > 0. { Facing::East } else { Facing::West }
+        } else if z > 0. { Facing::South } else { Facing::North }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Facing::North => "north",
+            Facing::South => "south",
+            Facing::East => "east",
+            Facing::West => "west"
+        }
+    }
+
+    /* North is towards -z and east towards +x */
+    pub fn offset(self) -> (i32, i32, i32) {
+        match self {
+            Facing::North => (0, 0, -1),
+            Facing::South => (0, 0, 1),
+            Facing::East => (1, 0, 0),
+            Facing::West => (-1, 0, 0)
+        }
+    }
+}
+
+/* Box inside a voxel, from 0 to 1 on every axis */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ModelBox {
+    pub min: [f32; 3],
+    pub max: [f32; 3]
+}
+
+impl ModelBox {
+    pub const FULL: ModelBox = ModelBox { min: [0., 0., 0.], max: [1., 1., 1.] };
+
+    /* Whether this box has a side lying on `plane` along `axis` that covers the side of `other` across the two remaining axes */
+    pub fn covers(&self, other: &ModelBox, axis: usize, plane: f32) -> bool {
+        (self.min[axis] == plane || self.max[axis] == plane) && (0..3).filter(|other_axis| *other_axis != axis)
+            .all(|other_axis| self.min[other_axis] <= other.min[other_axis] && self.max[other_axis] >= other.max[other_axis])
+    }
+}
+
+const FENCE_POST: ModelBox = ModelBox { min: [0.375, 0., 0.375], max: [0.625, 1., 0.625] };
+
+impl BlockModel {
+    /* Boxes making up the model, fences reach out towards the connected sides in the order of `Facing::ALL`. Crossed quads
+       have no boxes */
+    pub fn boxes(self, connections: [bool; 4]) -> Vec<ModelBox> {
+        match self {
+            BlockModel::Cube => vec![ModelBox::FULL],
+            BlockModel::Cross => Vec::new(),
+            BlockModel::Slab => vec![ModelBox { min: [0., 0., 0.], max: [1., 0.5, 1.] }],
+            BlockModel::Stairs(facing) => {
+                let step = match facing {
+                    Facing::North => ModelBox { min: [0., 0.5, 0.], max: [1., 1., 0.5] },
+                    Facing::South => ModelBox { min: [0., 0.5, 0.5], max: [1., 1., 1.] },
+                    Facing::East => ModelBox { min: [0.5, 0.5, 0.], max: [1., 1., 1.] },
+                    Facing::West => ModelBox { min: [0., 0.5, 0.], max: [0.5, 1., 1.] }
+                };
+                vec![ModelBox { min: [0., 0., 0.], max: [1., 0.5, 1.] }, step]
+            }
+            BlockModel::Fence => {
+                let mut boxes = vec![FENCE_POST];
+                for (facing, connected) in Facing::ALL.into_iter().zip(connections) {
+                    if !connected {
+                        continue;
+                    }
+                    let (min, max) = (FENCE_POST.min, FENCE_POST.max);
+                    // Rails run from the post to the side of the voxel
+                    boxes.push(match facing {
+                        Facing::North => ModelBox { min: [min[0] + 0.0625, 0.375, 0.], max: [max[0] - 0.0625, 0.9375, min[2]] },
+                        Facing::South => ModelBox { min: [min[0] + 0.0625, 0.375, max[2]], max: [max[0] - 0.0625, 0.9375, 1.] },
+                        Facing::East => ModelBox { min: [max[0], 0.375, min[2] + 0.0625], max: [1., 0.9375, max[2] - 0.0625] },
+                        Facing::West => ModelBox { min: [0., 0.375, min[2] + 0.0625], max: [min[0], 0.9375, max[2] - 0.0625] }
+                    });
+                }
+                boxes
+            }
+        }
+    }
+
+    /* Fences join other fences and the full blocks next to them */
+    pub fn connections(self, blocks: &BlockRegistry, neighbor: impl Fn(Facing) -> BlockId) -> [bool; 4] {
+        if self != BlockModel::Fence {
+            return [false; 4];
+        }
+        Facing::ALL.map(|facing| {
+            let block = neighbor(facing);
+            blocks.model(block) == BlockModel::Fence || blocks.is_opaque(block)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::world::block::test_blocks::{self, FENCE, SLAB, STONE};
+
+    #[test]
+    fn stairs_step_up_on_their_facing() {
+        let boxes = BlockModel::Stairs(Facing::East).boxes([false; 4]);
+        assert_eq!(boxes.len(), 2);
+        assert_eq!(boxes[1].min, [0.5, 0.5, 0.]);
+        // The bottom half covers the top of the lower step's side, the step covers the upper half of the east side
+        let east_upper = ModelBox { min: [1., 0.5, 0.], max: [1., 1., 1.] };
+        assert!(boxes[1].covers(&east_upper, 0, 1.));
+        assert!(!boxes[0].covers(&east_upper, 0, 1.));
+    }
+
+    #[test]
+    fn facing_follows_the_strongest_horizontal_direction() {
+        assert_eq!(Facing::from_direction(0.1, -0.9), Facing::North);
+        assert_eq!(Facing::from_direction(-0.2, 0.5), Facing::South);
+        assert_eq!(Facing::from_direction(0.7, 0.6), Facing::East);
+        assert_eq!(Facing::from_direction(-0.7, -0.6), Facing::West);
+    }
+
+    #[test]
+    fn fences_connect_to_fences_and_full_blocks() {
+        let blocks = test_blocks::registry();
+        let connections = BlockModel::Fence.connections(&blocks, |facing| match facing {
+            Facing::North => STONE,
+            Facing::South => FENCE,
+            Facing::East => SLAB,
+            Facing::West => BlockId::AIR
+        });
+        assert_eq!(connections, [true, true, false, false]);
+        let boxes = BlockModel::Fence.boxes(connections);
+        assert_eq!(boxes.len(), 3);
+        assert_eq!(boxes[1].min[2], 0.);
+        assert_eq!(boxes[2].max[2], 1.);
+    }
+}
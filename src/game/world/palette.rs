@@ -0,0 +1,179 @@
+use crate::game::world::chunk::CHUNK_VOLUME;
+use crate::game::world::voxel::Voxel;
+
+/* Voxel storage for a section, uniform sections keep a single voxel while others store indices into a palette
+   packed into u64 words with as few bits per index as the palette allows */
+#[derive(Clone)]
+pub enum PalettedStorage {
+    Uniform(Voxel),
+    Paletted {
+        palette: Vec<Voxel>,
+        bits: u32,
+        data: Vec<u64>
+    }
+}
+
+impl PalettedStorage {
+    pub fn new(voxel: Voxel) -> Self {
+        PalettedStorage::Uniform(voxel)
+    }
+
+    pub fn get(&self, index: usize) -> Voxel {
+        match self {
+            PalettedStorage::Uniform(voxel) => *voxel,
+            PalettedStorage::Paletted { palette, bits, data } => palette[Self::read_index(data, *bits, index)]
+        }
+    }
+
+    pub fn set(&mut self, index: usize, voxel: Voxel) {
+        if let PalettedStorage::Uniform(current) = self {
+            if *current == voxel {
+                return;
+            }
+            *self = PalettedStorage::Paletted {
+                palette: vec![*current],
+                bits: 1,
+                data: vec![0; Self::word_count(1)]
+            };
+        }
+
+        if let PalettedStorage::Paletted { palette, bits, data } = self {
+            let palette_index = match palette.iter().position(|entry| *entry == voxel) {
+                Some(palette_index) => palette_index,
+                None => {
+                    palette.push(voxel);
+                    if palette.len() > 1 << *bits {
+                        let new_bits = Self::bits_for(palette.len());
+                        *data = Self::repack(data, *bits, new_bits);
+                        *bits = new_bits;
+                    }
+                    palette.len() - 1
+                }
+            };
+            Self::write_index(data, *bits, index, palette_index);
+        }
+    }
+
+    /* Whether any voxel in the palette matches, palettes may still hold voxels that are no longer used until optimized */
+    pub fn any(&self, predicate: impl Fn(Voxel) -> bool) -> bool {
+        match self {
+            PalettedStorage::Uniform(voxel) => predicate(*voxel),
+            PalettedStorage::Paletted { palette, .. } => palette.iter().any(|voxel| predicate(*voxel))
+        }
+    }
+
+    /* Drops unused palette entries and collapses back to a uniform section when only one voxel remains */
+    pub fn optimize(&mut self) {
+        let (palette, bits, data) = match self {
+            PalettedStorage::Uniform(_) => return,
+            PalettedStorage::Paletted { palette, bits, data } => (palette, *bits, data)
+        };
+
+        let mut used = vec![false; palette.len()];
+        for index in 0..CHUNK_VOLUME {
+            used[Self::read_index(data, bits, index)] = true;
+        }
+        if used.iter().filter(|used| **used).count() == 1 {
+            let voxel = palette[used.iter().position(|used| *used).unwrap()];
+            *self = PalettedStorage::Uniform(voxel);
+            return;
+        }
+        if used.iter().all(|used| *used) {
+            return;
+        }
+
+        let mut optimized = PalettedStorage::Uniform(self.get(0));
+        for index in 0..CHUNK_VOLUME {
+            optimized.set(index, self.get(index));
+        }
+        *self = optimized;
+    }
+
+    /* Bytes allocated on the heap for the palette and index data */
+    pub fn heap_size(&self) -> usize {
+        match self {
+            PalettedStorage::Uniform(_) => 0,
+            PalettedStorage::Paletted { palette, data, .. } => {
+                palette.capacity() * std::mem::size_of::<Voxel>() + data.capacity() * std::mem::size_of::<u64>()
+            }
+        }
+    }
+
+    fn bits_for(palette_len: usize) -> u32 {
+        match usize::BITS - (palette_len - 1).leading_zeros() {
+            0..=1 => 1,
+            2 => 2,
+            3..=4 => 4,
+            5..=8 => 8,
+            _ => 16
+        }
+    }
+
+    /* Indices never straddle two words so the bit count is always a power of two */
+    fn word_count(bits: u32) -> usize {
+        CHUNK_VOLUME / (64 / bits) as usize
+    }
+
+    fn read_index(data: &[u64], bits: u32, index: usize) -> usize {
+        let per_word = (64 / bits) as usize;
+        let shift = (index % per_word) as u32 * bits;
+        ((data[index / per_word] >> shift) & ((1 << bits) - 1)) as usize
+    }
+
+    fn write_index(data: &mut [u64], bits: u32, index: usize, value: usize) {
+        let per_word = (64 / bits) as usize;
+        let shift = (index % per_word) as u32 * bits;
+        let mask = ((1u64 << bits) - 1) << shift;
+        let word = &mut data[index / per_word];
+        *word = (*word & !mask) | ((value as u64) << shift);
+    }
+
+    fn repack(data: &[u64], bits: u32, new_bits: u32) -> Vec<u64> {
+        let mut repacked = vec![0; Self::word_count(new_bits)];
+        for index in 0..CHUNK_VOLUME {
+            Self::write_index(&mut repacked, new_bits, index, Self::read_index(data, bits, index));
+        }
+        repacked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::world::block::BlockId;
+
+    fn voxel(id: u16) -> Voxel {
+        Voxel::new(BlockId(id))
+    }
+
+    #[test]
+    fn grows_bits_with_palette() {
+        let mut storage = PalettedStorage::new(Voxel::air());
+        assert_eq!(storage.heap_size(), 0);
+
+        storage.set(10, voxel(1));
+        storage.set(CHUNK_VOLUME - 1, voxel(2));
+        storage.set(20, voxel(3));
+        match &storage {
+            PalettedStorage::Paletted { bits, palette, .. } => {
+                assert_eq!(*bits, 2);
+                assert_eq!(palette.len(), 4);
+            }
+            PalettedStorage::Uniform(_) => panic!("Expected paletted storage")
+        }
+        assert!(storage.get(10) == voxel(1));
+        assert!(storage.get(CHUNK_VOLUME - 1) == voxel(2));
+        assert!(storage.get(20) == voxel(3));
+        assert!(storage.get(0) == Voxel::air());
+    }
+
+    #[test]
+    fn optimize_collapses_to_uniform() {
+        let mut storage = PalettedStorage::new(Voxel::air());
+        for index in 0..CHUNK_VOLUME {
+            storage.set(index, voxel(3));
+        }
+        storage.optimize();
+        assert!(matches!(storage, PalettedStorage::Uniform(voxel) if voxel.block == BlockId(3)));
+    }
+}
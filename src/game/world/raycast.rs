@@ -0,0 +1,123 @@
+use bevy::prelude::*;
+use crate::game::world::world::World;
+
+pub struct RaycastHit {
+    /* The solid voxel that was hit */
+    pub position: (i32, i32, i32),
+    /* Normal of the face the ray entered through, zero if the ray started inside the voxel */
+    pub normal: (i32, i32, i32),
+    pub distance: f32,
+    /* The last empty voxel the ray passed through before the hit */
+    pub previous: (i32, i32, i32),
+}
+
+impl World {
+    /* Steps voxel by voxel along the ray (Amanatides & Woo DDA), unloaded chunks are treated as empty space */
+    pub fn raycast(&self, origin: Vec3, direction: Vec3, max_distance: f32) -> Option<RaycastHit> {
+        let direction = direction.normalize_or_zero();
+        if direction == Vec3::ZERO {
+            return None;
+        }
+
+        let origin = origin.to_array();
+        let direction = direction.to_array();
+        let mut voxel = [0; 3];
+        let mut step = [0; 3];
+        let mut t_max = [f32::INFINITY; 3];
+        let mut t_delta = [f32::INFINITY; 3];
+        for axis in 0..3 {
+            voxel[axis] = origin[axis].floor() as i32;
+            if direction[axis] > 0. {
+                step[axis] = 1;
+                t_max[axis] = (origin[axis].floor() + 1. - origin[axis]) / direction[axis];
+                t_delta[axis] = 1. / direction[axis];
+            } else if direction[axis] < 0. {
+                step[axis] = -1;
+                t_max[axis] = (origin[axis] - origin[axis].floor()) / -direction[axis];
+                t_delta[axis] = 1. / -direction[axis];
+            }
+        }
+
+        let mut previous = voxel;
+        let mut normal = [0; 3];
+        let mut distance = 0.;
+        while distance <= max_distance {
+            let position = (voxel[0], voxel[1], voxel[2]);
+            if let Some(hit) = self.get_voxel(position) {
+                if !hit.block.is_air() {
+                    return Some(RaycastHit {
+                        position,
+                        normal: (normal[0], normal[1], normal[2]),
+                        distance,
+                        previous: (previous[0], previous[1], previous[2])
+                    });
+                }
+            }
+
+            let axis = if t_max[0] < t_max[1] {
+                if t_max[0] < t_max[2] { 0 } else { 2 }
+            } else if t_max[1] < t_max[2] { 1 } else { 2 };
+
+            previous = voxel;
+            distance = t_max[axis];
+            voxel[axis] += step[axis];
+            t_max[axis] += t_delta[axis];
+            normal = [0; 3];
+            normal[axis] = -step[axis];
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::world::block::test_blocks::STONE;
+
+    #[test]
+    fn axis_aligned_ray_hits_the_top_face() {
+        let world = World::with_voxels(&[((2, 1, 3), STONE)]);
+        let hit = world.raycast(Vec3::new(2.5, 6.5, 3.5), -Vec3::Y, 10.).unwrap();
+        assert_eq!(hit.position, (2, 1, 3));
+        assert_eq!(hit.normal, (0, 1, 0));
+        assert_eq!(hit.previous, (2, 2, 3));
+        assert!((hit.distance - 4.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn diagonal_ray_enters_through_the_side_it_crosses_first() {
+        let world = World::with_voxels(&[((4, 4, 4), STONE)]);
+        let hit = world.raycast(Vec3::new(0.2, 0.5, 0.7), Vec3::new(1., 1., 1.), 10.).unwrap();
+        assert_eq!(hit.position, (4, 4, 4));
+        assert_eq!(hit.normal, (-1, 0, 0));
+        assert_eq!(hit.previous, (3, 4, 4));
+        assert!((hit.distance - 3.8 * 3f32.sqrt()).abs() < 1e-4);
+    }
+
+    #[test]
+    fn negative_ray_crosses_into_neighboring_chunks() {
+        let world = World::with_voxels(&[((-3, 5, -1), STONE)]);
+        let hit = world.raycast(Vec3::new(5.5, 5.5, -0.5), -Vec3::X, 10.).unwrap();
+        assert_eq!(hit.position, (-3, 5, -1));
+        assert_eq!(hit.normal, (1, 0, 0));
+        assert_eq!(hit.previous, (-2, 5, -1));
+        assert!((hit.distance - 7.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn ray_starting_inside_a_block_hits_it_immediately() {
+        let world = World::with_voxels(&[((1, 1, 1), STONE)]);
+        let hit = world.raycast(Vec3::new(1.5, 1.5, 1.5), Vec3::X, 10.).unwrap();
+        assert_eq!(hit.position, (1, 1, 1));
+        assert_eq!(hit.normal, (0, 0, 0));
+        assert_eq!(hit.distance, 0.);
+    }
+
+    #[test]
+    fn ray_misses_blocks_past_its_max_distance() {
+        let world = World::with_voxels(&[((0, 0, 10), STONE)]);
+        assert!(world.raycast(Vec3::new(0.5, 0.5, 0.5), Vec3::Z, 5.).is_none());
+        assert!(world.raycast(Vec3::new(0.5, 0.5, 0.5), Vec3::Z, 10.).is_some());
+        assert!(world.raycast(Vec3::new(0.5, 0.5, 0.5), -Vec3::Z, 20.).is_none());
+    }
+}
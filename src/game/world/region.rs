@@ -0,0 +1,120 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::game::world::chunk::Chunk;
+
+/* Regions are squares of REGION_LENGTH x REGION_LENGTH chunks stored in a single file */
+pub const REGION_LENGTH: i32 = 32;
+const REGION_AREA: usize = (REGION_LENGTH * REGION_LENGTH) as usize;
+/* Each header entry is a u32 byte offset followed by a u32 byte length, an offset of zero means the chunk was never saved */
+const HEADER_SIZE: u64 = REGION_AREA as u64 * 8;
+const METADATA_FILE: &str = "world.ron";
+
+/* Reads and writes zlib compressed chunks to region files inside a save directory */
+#[derive(Clone)]
+pub struct RegionStorage {
+    directory: PathBuf
+}
+
+impl RegionStorage {
+    pub fn new(directory: PathBuf) -> Self {
+        Self { directory }
+    }
+
+    pub fn load_chunk(&self, position: (i32, i32)) -> io::Result<Option<Chunk>> {
+        let mut file = match File::open(self.region_path(position)) {
+            Ok(file) => file,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error)
+        };
+        let (offset, length) = Self::read_entry(&mut file, position)?;
+        if offset == 0 {
+            return Ok(None);
+        }
+
+        let mut compressed = vec![0; length as usize];
+        file.seek(SeekFrom::Start(offset as u64))?;
+        file.read_exact(&mut compressed)?;
+        let mut bytes = Vec::new();
+        ZlibDecoder::new(compressed.as_slice()).read_to_end(&mut bytes)?;
+
+        match Chunk::deserialize(position, &bytes) {
+            Some(chunk) => Ok(Some(chunk)),
+            None => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Corrupt chunk data at {:?}", position)))
+        }
+    }
+
+    /* Overwrites the chunk's previous data when the new data fits, otherwise appends it to the end of the file */
+    pub fn save_chunk(&self, chunk: &Chunk) -> io::Result<()> {
+        let position = chunk.get_position();
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&chunk.serialize())?;
+        let compressed = encoder.finish()?;
+
+        fs::create_dir_all(&self.directory)?;
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(self.region_path(position))?;
+        if file.metadata()?.len() < HEADER_SIZE {
+            file.set_len(HEADER_SIZE)?;
+        }
+
+        let (old_offset, old_length) = Self::read_entry(&mut file, position)?;
+        let offset = if old_offset != 0 && compressed.len() as u32 <= old_length {
+            old_offset as u64
+        } else {
+            file.seek(SeekFrom::End(0))?
+        };
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&compressed)?;
+
+        file.seek(SeekFrom::Start(Self::entry_offset(position)))?;
+        file.write_all(&(offset as u32).to_le_bytes())?;
+        file.write_all(&(compressed.len() as u32).to_le_bytes())?;
+        Ok(())
+    }
+
+    fn read_entry(file: &mut File, position: (i32, i32)) -> io::Result<(u32, u32)> {
+        let mut entry = [0; 8];
+        file.seek(SeekFrom::Start(Self::entry_offset(position)))?;
+        match file.read_exact(&mut entry) {
+            Ok(()) => Ok((
+                u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]),
+                u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]])
+            )),
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => Ok((0, 0)),
+            Err(error) => Err(error)
+        }
+    }
+
+    fn entry_offset(position: (i32, i32)) -> u64 {
+        let x = position.0.rem_euclid(REGION_LENGTH);
+        let z = position.1.rem_euclid(REGION_LENGTH);
+        (z * REGION_LENGTH + x) as u64 * 8
+    }
+
+    /* World wide data such as the generator settings, kept as RON next to the regions */
+    pub fn load_metadata<T: DeserializeOwned>(&self) -> io::Result<Option<T>> {
+        let source = match fs::read_to_string(self.directory.join(METADATA_FILE)) {
+            Ok(source) => source,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error)
+        };
+        ron::from_str(&source).map(Some).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string()))
+    }
+
+    pub fn save_metadata<T: Serialize>(&self, metadata: &T) -> io::Result<()> {
+        let source = ron::ser::to_string_pretty(metadata, ron::ser::PrettyConfig::default())
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string()))?;
+        fs::create_dir_all(&self.directory)?;
+        fs::write(self.directory.join(METADATA_FILE), source)
+    }
+
+    fn region_path(&self, position: (i32, i32)) -> PathBuf {
+        let region = (position.0.div_euclid(REGION_LENGTH), position.1.div_euclid(REGION_LENGTH));
+        self.directory.join(format!("r.{}.{}.region", region.0, region.1))
+    }
+}
@@ -0,0 +1,153 @@
+use std::f32::consts::TAU;
+use bevy::prelude::*;
+
+/* New worlds start in the morning */
+pub const START_TIME: f32 = 0.3;
+const DAY_LENGTH: f32 = 1200.;
+
+/* Looks of the sky keyed by the sun's height, from -1 at midnight through 0 at the horizon to 1 at noon. Below the horizon
+   the light comes from the moon instead */
+struct SkyKey {
+    sun_height: f32,
+    sky_color: [f32; 3],
+    light_color: [f32; 3],
+    illuminance: f32,
+    ambient: f32
+}
+
+const SKY_KEYS: [SkyKey; 5] = [
+    SkyKey { sun_height: -1., sky_color: [0.01, 0.01, 0.05], light_color: [0.6, 0.7, 1.], illuminance: 600., ambient: 0.02 },
+    SkyKey { sun_height: -0.1, sky_color: [0.01, 0.01, 0.05], light_color: [0.6, 0.7, 1.], illuminance: 600., ambient: 0.02 },
+    SkyKey { sun_height: 0., sky_color: [0.75, 0.4, 0.25], light_color: [1., 0.6, 0.35], illuminance: 300., ambient: 0.03 },
+    SkyKey { sun_height: 0.3, sky_color: [0.1, 0.4, 0.6], light_color: [1., 1., 1.], illuminance: 10000., ambient: 0.05 },
+    SkyKey { sun_height: 1., sky_color: [0.1, 0.4, 0.6], light_color: [1., 1., 1.], illuminance: 10000., ambient: 0.05 }
+];
+
+/* Time of day as a fraction of a day starting at midnight, so 0.25 is sunrise, 0.5 noon and 0.75 sunset */
+pub struct WorldTime {
+    time_of_day: f32,
+    /* Real seconds a whole day takes */
+    pub day_length: f32,
+    paused: bool
+}
+
+impl Default for WorldTime {
+    fn default() -> Self {
+        Self {
+            time_of_day: START_TIME,
+            day_length: DAY_LENGTH,
+            paused: false
+        }
+    }
+}
+
+impl WorldTime {
+    pub fn time_of_day(&self) -> f32 {
+        self.time_of_day
+    }
+
+    pub fn set_time_of_day(&mut self, time_of_day: f32) {
+        self.time_of_day = time_of_day.rem_euclid(1.);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn advance(&mut self, seconds: f32) {
+        if !self.paused {
+            self.set_time_of_day(self.time_of_day + seconds / self.day_length);
+        }
+    }
+
+    /* Direction towards the sun, it rises in the east along +x and is tilted a little south so it never stands straight overhead */
+    fn sun_direction(&self) -> Vec3 {
+        let angle = (self.time_of_day - 0.25) * TAU;
+        Vec3::new(angle.cos(), angle.sin(), 0.35).normalize()
+    }
+}
+
+/* The directional light following the sun by day and the moon by night */
+#[derive(Component)]
+pub struct Sun;
+
+pub fn advance_world_time(time: Res<Time>, mut world_time: ResMut<WorldTime>) {
+    world_time.advance(time.delta_seconds());
+}
+
+/* P pauses and resumes the clock, T skips ahead an hour */
+pub fn time_controls(inputs: Res<Input<KeyCode>>, mut world_time: ResMut<WorldTime>) {
+    if inputs.just_pressed(KeyCode::P) {
+        let paused = !world_time.is_paused();
+        world_time.set_paused(paused);
+    }
+    if inputs.just_pressed(KeyCode::T) {
+        let time_of_day = world_time.time_of_day() + 1. / 24.;
+        world_time.set_time_of_day(time_of_day);
+    }
+}
+
+pub fn update_sun(
+    world_time: Res<WorldTime>,
+    mut clear_color: ResMut<ClearColor>,
+    mut ambient_light: ResMut<AmbientLight>,
+    mut sun_query: Query<(&mut Transform, &mut DirectionalLight), With<Sun>>
+) {
+    let sun_direction = world_time.sun_direction();
+    let sky = sample_sky(sun_direction.y);
+    clear_color.0 = Color::rgb(sky.sky_color[0], sky.sky_color[1], sky.sky_color[2]);
+    ambient_light.brightness = sky.ambient;
+
+    let light_direction = if sun_direction.y >= 0. { sun_direction } else { -sun_direction };
+    for (mut transform, mut light) in sun_query.iter_mut() {
+        *transform = Transform::identity().looking_at(-light_direction, Vec3::Z);
+        light.color = Color::rgb(sky.light_color[0], sky.light_color[1], sky.light_color[2]);
+        light.illuminance = sky.illuminance;
+    }
+}
+
+/* Linearly blends the two keys around the sun height */
+fn sample_sky(sun_height: f32) -> SkyKey {
+    let next = SKY_KEYS.iter().position(|key| key.sun_height >= sun_height).unwrap_or(SKY_KEYS.len() - 1).max(1);
+    let (from, to) = (&SKY_KEYS[next - 1], &SKY_KEYS[next]);
+    let t = ((sun_height - from.sun_height) / (to.sun_height - from.sun_height)).clamp(0., 1.);
+    let lerp = |from: f32, to: f32| from + (to - from) * t;
+    let lerp3 = |from: [f32; 3], to: [f32; 3]| [lerp(from[0], to[0]), lerp(from[1], to[1]), lerp(from[2], to[2])];
+    SkyKey {
+        sun_height,
+        sky_color: lerp3(from.sky_color, to.sky_color),
+        light_color: lerp3(from.light_color, to.light_color),
+        illuminance: lerp(from.illuminance, to.illuminance),
+        ambient: lerp(from.ambient, to.ambient)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_wraps_and_pauses() {
+        let mut time = WorldTime { day_length: 100., ..default() };
+        time.set_time_of_day(0.9);
+        time.advance(20.);
+        assert!((time.time_of_day() - 0.1).abs() < 1e-5);
+
+        time.set_paused(true);
+        time.advance(20.);
+        assert!((time.time_of_day() - 0.1).abs() < 1e-5);
+        assert!(time.sun_direction().y < 0.);
+    }
+
+    #[test]
+    fn sky_blends_between_keys() {
+        assert_eq!(sample_sky(1.).illuminance, 10000.);
+        assert_eq!(sample_sky(-1.).sky_color, SKY_KEYS[0].sky_color);
+        assert_eq!(sample_sky(0.15).illuminance, (300. + 10000.) / 2.);
+        assert_eq!(sample_sky(-2.).illuminance, 600.);
+    }
+}
@@ -1,18 +1,16 @@
-#[derive(Clone, Copy)]
+use crate::game::world::block::BlockId;
+
+#[derive(Clone, Copy, Eq, PartialEq)]
 pub struct Voxel {
-    pub material: VoxelType
+    pub block: BlockId
 }
 
 impl Voxel {
     pub fn air() -> Self {
-        Self { material: VoxelType::Air }
+        Self { block: BlockId::AIR }
     }
-}
 
-#[derive(Eq, PartialEq, Clone, Copy)]
-pub enum VoxelType {
-    Air,
-    Grass,
-    Dirt,
-    Stone,
-}
\ No newline at end of file
+    pub fn new(block: BlockId) -> Self {
+        Self { block }
+    }
+}
@@ -1,54 +1,330 @@
-use std::future::Future;
-use std::task::Poll;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use bevy::app::AppExit;
+use bevy::diagnostic::{Diagnostic, DiagnosticId, Diagnostics};
 use bevy::prelude::*;
 use bevy::tasks::{AsyncComputeTaskPool, Task};
-use bevy::utils::HashMap;
+use bevy::utils::{HashMap, HashSet};
 use futures_lite::future;
-use futures_lite::future::FutureExt;
+use serde::{Deserialize, Serialize};
 use crate::game::player::PlayerController;
-use crate::game::world::chunk;
-use crate::game::world::chunk::{Chunk, CHUNK_LENGTH};
-use crate::game::world::generator::TerrainGenerator;
-use crate::game::world::voxel::VoxelType;
+use crate::game::world::chunk::{Chunk, ChunkNeighborhood, ChunkSection, CHUNK_LENGTH, MemoryUsage, MeshLayer, MeshingMode, SectionMeshes};
+use crate::game::world::atlas::BlockAtlas;
+use crate::game::world::block::{BlockId, BlockRegistry};
+use crate::game::world::light::LightEngine;
+use crate::game::world::generator::{Biome, Decoration, GeneratorSettings, TerrainBlocks, TerrainGenerator};
+use crate::game::world::material::VoxelMaterial;
+use crate::game::world::region::RegionStorage;
+use crate::game::world::time::{Sun, WorldTime, START_TIME};
+use crate::game::world::voxel::Voxel;
 
 #[derive(Component)]
 pub struct Terrain;
 
+const BLOCKS_PATH: &str = "assets/blocks.ron";
+const BLOCK_TEXTURES_PATH: &str = "assets/textures/blocks";
+
+pub const VOXEL_MEMORY: DiagnosticId = DiagnosticId::from_u128(219663812373450273859361474133925446617);
+pub const VOXEL_MEMORY_UNCOMPRESSED: DiagnosticId = DiagnosticId::from_u128(117493857204518376590198277123509467103);
+
+/* Chunk distances are measured in chunks from the player's chunk, the gap between the radii stops chunks on the edge from reloading constantly */
+pub struct WorldSettings {
+    pub load_radius: i32,
+    pub unload_radius: i32,
+    pub max_generation_tasks: usize,
+    /* Finished chunks added to the world per frame, each one is lit with a flood fill through its whole column */
+    pub max_chunk_loads: usize,
+    /* Dirty sections rebuilt per frame, the rest wait for later frames nearest first */
+    pub max_section_meshes: usize,
+    /* Directory region files are saved to, None disables saving */
+    pub save_directory: Option<PathBuf>,
+    /* Used for new worlds, saved worlds keep the settings they were created with */
+    pub generator: GeneratorSettings,
+    pub meshing_mode: MeshingMode
+}
+
+impl Default for WorldSettings {
+    fn default() -> Self {
+        Self {
+            load_radius: 15,
+            unload_radius: 17,
+            max_generation_tasks: 16,
+            max_chunk_loads: 4,
+            max_section_meshes: 32,
+            save_directory: Some(PathBuf::from("saves/world")),
+            generator: GeneratorSettings::default(),
+            meshing_mode: MeshingMode::default()
+        }
+    }
+}
+
+/* Everything about a world that isn't stored per chunk */
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+struct WorldMetadata {
+    generator: GeneratorSettings,
+    time_of_day: f32
+}
+
+impl Default for WorldMetadata {
+    fn default() -> Self {
+        Self {
+            generator: GeneratorSettings::default(),
+            time_of_day: START_TIME
+        }
+    }
+}
+
+/* Every section has an entity per mesh layer that has faces */
+type SectionLayer = ((i32, i32, i32), MeshLayer);
+
 #[derive(Default)]
 pub struct World {
     terrain_entity: Option<Entity>,
-    terrain_material: Handle<StandardMaterial>,
+    terrain_material: Handle<VoxelMaterial>,
+    translucent_material: Handle<VoxelMaterial>,
     chunk_ledger: HashMap<(i32, i32), Chunk>,
     bevy_chunk_ledger: HashMap<(i32, i32), Entity>,
-    loading_ledger: HashMap<(i32, i32), Task<(Chunk)>>,
-    generator: TerrainGenerator
+    loading_ledger: HashMap<(i32, i32), Task<Chunk>>,
+    generation_queue: BinaryHeap<Reverse<(i32, (i32, i32))>>,
+    queue_center: Option<(i32, i32)>,
+    bevy_section_ledger: HashMap<SectionLayer, Handle<Mesh>>,
+    dirty_sections: HashSet<(i32, i32, i32)>,
+    generator: Arc<TerrainGenerator>,
+    storage: Option<RegionStorage>,
+    /* Set when the saved metadata couldn't be read, the file is then left alone instead of being replaced with the defaults */
+    metadata_unreadable: bool,
+    pub blocks: BlockRegistry,
+    atlas: BlockAtlas,
+    terrain_blocks: TerrainBlocks,
+    meshing_mode: MeshingMode
 }
 
 impl World {
     fn create_chunk(&mut self, position: (i32, i32), loading_pool: &Res<AsyncComputeTaskPool>,) {
-        let loading_task: Task<Chunk> = Self::generate_chunk(position, TerrainGenerator::default(), loading_pool);
+        let loading_task: Task<Chunk> = Self::generate_chunk(position, self.generator.clone(), self.terrain_blocks.clone(), self.storage.clone(), loading_pool);
         self.loading_ledger.insert(position, loading_task);
     }
 
-    fn generate_chunk(position: (i32, i32), generator: TerrainGenerator, loading_pool: &Res<AsyncComputeTaskPool>) -> Task<Chunk> {
+    /* Loads the chunk from its region file if it was saved before, otherwise generates it */
+    fn generate_chunk(position: (i32, i32), generator: Arc<TerrainGenerator>, blocks: TerrainBlocks, storage: Option<RegionStorage>, loading_pool: &Res<AsyncComputeTaskPool>) -> Task<Chunk> {
         loading_pool.spawn(async move  {
+            if let Some(storage) = storage {
+                match storage.load_chunk(position) {
+                    Ok(Some(chunk)) => return chunk,
+                    Ok(None) => {}
+                    Err(error) => error!("Failed to load chunk {:?}: {}", position, error)
+                }
+            }
             let mut chunk = Chunk::new(position);
-            chunk.generate_voxels(&generator);
-            chunk.generate_chunk_meshes();
+            chunk.generate_voxels(&generator, &blocks);
             chunk
         })
     }
 
-    fn render_chunk(&mut self, position: (i32, i32), loading_pool: &Res<AsyncComputeTaskPool>,) {
-        if !self.loading_ledger.contains_key(&position) && !self.chunk_ledger.contains_key(&position) {
-            self.create_chunk(position, loading_pool);
+    fn save_chunk(&self, chunk: &Chunk) {
+        if !chunk.is_edited() {
+            return;
+        }
+        if let Some(storage) = &self.storage {
+            if let Err(error) = storage.save_chunk(chunk) {
+                error!("Failed to save chunk {:?}: {}", chunk.get_position(), error);
+            }
+        }
+    }
+
+    pub fn save_all_chunks(&self) {
+        for chunk in self.chunk_ledger.values() {
+            self.save_chunk(chunk);
+        }
+    }
+
+    /* Rebuilds the generation queue whenever the player enters a new chunk so the nearest missing chunks are generated first */
+    fn queue_chunks(&mut self, center: (i32, i32), load_radius: i32) {
+        if self.queue_center == Some(center) {
+            return;
+        }
+        self.queue_center = Some(center);
+        self.generation_queue.clear();
+        debug!("Entered chunk {:?} in {:?} biome", center, self.biome_at(center.0 * CHUNK_LENGTH as i32, center.1 * CHUNK_LENGTH as i32));
+        for x in center.0 - load_radius..=center.0 + load_radius {
+            for z in center.1 - load_radius..=center.1 + load_radius {
+                let position = (x, z);
+                if self.loading_ledger.contains_key(&position) || self.chunk_ledger.contains_key(&position) {
+                    continue;
+                }
+                let distance = (x - center.0).pow(2) + (z - center.1).pow(2);
+                self.generation_queue.push(Reverse((distance, position)));
+            }
+        }
+    }
+
+    fn start_generation_tasks(&mut self, max_tasks: usize, loading_pool: &Res<AsyncComputeTaskPool>) {
+        while self.loading_ledger.len() < max_tasks {
+            let position = match self.generation_queue.pop() {
+                Some(Reverse((_, position))) => position,
+                None => break
+            };
+            if !self.loading_ledger.contains_key(&position) && !self.chunk_ledger.contains_key(&position) {
+                self.create_chunk(position, loading_pool);
+            }
+        }
+    }
+
+    /* Drops the chunk data and despawns its entities, the section meshes are freed along with their last handle */
+    fn unload_chunk(&mut self, position: (i32, i32), commands: &mut Commands) {
+        if let Some(chunk) = self.chunk_ledger.remove(&position) {
+            self.save_chunk(&chunk);
+        }
+        self.dirty_sections.retain(|section| (section.0, section.2) != position);
+        self.bevy_section_ledger.retain(|(section, _), _| (section.0, section.2) != position);
+        if let Some(bevy_chunk) = self.bevy_chunk_ledger.remove(&position) {
+            commands.entity(bevy_chunk).despawn_recursive();
         }
     }
 
-    fn load_chunks(&mut self, commands: &mut Commands, meshes: &mut ResMut<Assets<Mesh>>, materials: &mut ResMut<Assets<StandardMaterial>>) {
-        info!("{}", self.loading_ledger.len());
+    fn unload_distant_chunks(&mut self, center: (i32, i32), unload_radius: i32, commands: &mut Commands) {
+        let distant_chunks: Vec<(i32, i32)> = self.chunk_ledger.keys()
+            .filter(|position| chunk_distance(center, **position) > unload_radius)
+            .copied()
+            .collect();
+        for position in distant_chunks {
+            self.unload_chunk(position, commands);
+        }
+
+        // Dropping a task cancels it
+        self.loading_ledger.retain(|position, _| chunk_distance(center, *position) <= unload_radius);
+    }
+
+    /* Gets the voxel at a world position, returns None if the chunk holding it isn't loaded */
+    pub fn get_voxel(&self, position: (i32, i32, i32)) -> Option<Voxel> {
+        let (chunk_position, local_position) = split_voxel_position(position);
+        let chunk = self.chunk_ledger.get(&chunk_position)?;
+        Some(chunk.get_voxel(local_position))
+    }
+
+    /* Sets the voxel at a world position and marks the meshes it touches for rebuilding, returns false if the chunk isn't loaded */
+    pub fn set_voxel(&mut self, position: (i32, i32, i32), block: BlockId) -> bool {
+        let (chunk_position, local_position) = split_voxel_position(position);
+        let chunk = match self.chunk_ledger.get_mut(&chunk_position) {
+            Some(chunk) => chunk,
+            None => return false
+        };
+        chunk.set_voxel(local_position, block);
+        chunk.mark_edited();
+        self.dirty_sections.extend(touched_sections(position));
+        self.relight(None, &[position]);
+        true
+    }
+
+    /* Lights a newly loaded chunk and relights around changed voxels, remeshing every section whose light changed */
+    fn relight(&mut self, chunk: Option<(i32, i32)>, changed_voxels: &[(i32, i32, i32)]) {
+        let mut engine = LightEngine::new(&mut self.chunk_ledger, &self.blocks);
+        if let Some(chunk) = chunk {
+            engine.light_chunk(chunk);
+        }
+        for position in changed_voxels {
+            engine.update_voxel(*position);
+        }
+        self.dirty_sections.extend(engine.into_changed());
+    }
+
+    /* Hands decoration blocks that overflowed the chunk's borders over between it and its loaded neighbors in both directions.
+       The receiving chunk records which neighbors it received from so broken blocks don't grow back after a reload, and is
+       only marked edited when a block was actually placed. Returns the voxels set in the neighbors */
+    fn exchange_overflow(&mut self, position: (i32, i32)) -> Vec<(i32, i32, i32)> {
+        let mut changed_voxels = Vec::new();
+        let reach = (Decoration::MAX_REACH + CHUNK_LENGTH as i32 - 1) / CHUNK_LENGTH as i32;
+        for x in position.0 - reach..=position.0 + reach {
+            for z in position.1 - reach..=position.1 + reach {
+                let neighbor = (x, z);
+                if neighbor == position || !self.chunk_ledger.contains_key(&neighbor) {
+                    continue;
+                }
+                for (source, receiver) in [(neighbor, position), (position, neighbor)] {
+                    if self.chunk_ledger[&receiver].has_received_overflow(source) {
+                        continue;
+                    }
+                    let blocks: Vec<((i32, i32, i32), BlockId)> = self.chunk_ledger[&source].overflow_into(receiver).collect();
+                    if blocks.is_empty() {
+                        continue;
+                    }
+                    let receiving_chunk = self.chunk_ledger.get_mut(&receiver).unwrap();
+                    receiving_chunk.record_received_overflow(source);
+                    for (block_position, block) in blocks {
+                        let (_, local_position) = split_voxel_position(block_position);
+                        if receiving_chunk.fill_air(local_position, block) {
+                            receiving_chunk.mark_edited();
+                            self.dirty_sections.extend(touched_sections(block_position));
+                            if receiver != position {
+                                changed_voxels.push(block_position);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        changed_voxels
+    }
+
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let mut usage = MemoryUsage::default();
+        for chunk in self.chunk_ledger.values() {
+            usage += chunk.memory_usage();
+        }
+        usage
+    }
+
+    fn get_section(&self, position: (i32, i32, i32)) -> Option<&ChunkSection> {
+        self.chunk_ledger.get(&(position.0, position.2))?.get_section(position.1)
+    }
+
+    /* Switching modes rebuilds every loaded section */
+    pub fn set_meshing_mode(&mut self, mode: MeshingMode) {
+        if self.meshing_mode == mode {
+            return;
+        }
+        self.meshing_mode = mode;
+        let positions: Vec<(i32, i32)> = self.chunk_ledger.keys().copied().collect();
+        for position in positions {
+            self.mark_chunk_dirty(position);
+        }
+    }
+
+    fn mark_chunk_dirty(&mut self, position: (i32, i32)) {
+        let sections = match self.chunk_ledger.get(&position) {
+            Some(chunk) => chunk.section_range(),
+            None => return
+        };
+        for y in sections {
+            self.dirty_sections.insert((position.0, y, position.1));
+        }
+    }
+
+    /* Builds the mesh of a section, looking into neighboring sections and chunks for face culling */
+    fn mesh_section(&mut self, position: (i32, i32, i32)) -> bool {
+        let section = match self.get_section(position) {
+            Some(section) => section,
+            None => return false
+        };
+        let meshes: SectionMeshes = ChunkNeighborhood::new(section, |neighbor| self.get_section(neighbor)).build_mesh(self.meshing_mode, &self.blocks, &self.atlas);
+        self.chunk_ledger.get_mut(&(position.0, position.2)).unwrap().set_section_meshes(position.1, meshes);
+        true
+    }
+
+    /* Adds up to `max_chunks` finished chunks to the world, nearest the player first, the rest stay in their finished tasks
+       until a later frame */
+    fn load_chunks(&mut self, max_chunks: usize, max_section_meshes: usize, commands: &mut Commands, meshes: &mut ResMut<Assets<Mesh>>, materials: &mut ResMut<Assets<VoxelMaterial>>) {
+        let center = self.queue_center.unwrap_or_default();
+        let mut loading: Vec<(&(i32, i32), &mut Task<Chunk>)> = self.loading_ledger.iter_mut().collect();
+        loading.sort_unstable_by_key(|(position, _)| chunk_distance(center, **position));
         let mut chunks: Vec<((i32, i32), Chunk)> = Vec::new();
-        for (position, loading_task) in self.loading_ledger.iter_mut() {
+        for (position, loading_task) in loading {
+            if chunks.len() >= max_chunks {
+                break;
+            }
             let result: Option<Chunk> = future::block_on(future::poll_once(loading_task));
             if result.is_some() {
                 chunks.push((position.clone(), result.unwrap()));
@@ -57,44 +333,147 @@ impl World {
         while !chunks.is_empty() {
             let (position, chunk) = chunks.remove(0);
             self.loading_ledger.remove(&position);
-            self.chunk_ledger.insert(position.clone(), chunk);
-            self.generate_bevy_chunk(position.clone(), commands, meshes, materials);
-        }
-    }
-
-    fn generate_bevy_chunk(&mut self, position: (i32, i32), commands: &mut Commands, meshes: &mut ResMut<Assets<Mesh>>, materials: &mut ResMut<Assets<StandardMaterial>>) {
-        let chunk = self.chunk_ledger.get_mut(&position).unwrap();
-        if chunk.is_mesh_calculated() {
-            let parent_chunk = commands.spawn().id();
-            commands.entity(self.terrain_entity.unwrap()).add_child(parent_chunk);
-            let sections = chunk.generate_bevy_meshes();
-            for mesh in sections {
-                let section = commands.spawn_bundle(PbrBundle {
-                    mesh: meshes.add(mesh),
-                    material: materials.get_handle(&self.terrain_material),
-                    ..default()
-                }).id();
-                commands.entity(parent_chunk).add_child(section);
+            self.chunk_ledger.insert(position, chunk);
+
+            // Neighbors that were meshed against a missing chunk have their border faces exposed
+            self.mark_chunk_dirty(position);
+            for neighbor in [(position.0 + 1, position.1), (position.0 - 1, position.1), (position.0, position.1 + 1), (position.0, position.1 - 1)] {
+                self.mark_chunk_dirty(neighbor);
+            }
+            let changed_voxels = self.exchange_overflow(position);
+            self.relight(Some(position), &changed_voxels);
+        }
+
+        self.remesh_dirty_sections(max_section_meshes, commands, meshes, materials);
+    }
+
+    /* Rebuilds up to `max_sections` of the sections marked dirty, nearest the player first so edits show up straight away while
+       freshly loaded chunks are spread over several frames. Reuses the existing mesh asset of a section when it has one */
+    fn remesh_dirty_sections(&mut self, max_sections: usize, commands: &mut Commands, meshes: &mut ResMut<Assets<Mesh>>, materials: &mut ResMut<Assets<VoxelMaterial>>) {
+        let center = self.queue_center.unwrap_or_default();
+        let mut dirty_sections: Vec<(i32, i32, i32)> = self.dirty_sections.iter().copied().collect();
+        dirty_sections.sort_unstable_by_key(|section| chunk_distance(center, (section.0, section.2)));
+        dirty_sections.truncate(max_sections);
+        for position in dirty_sections {
+            self.dirty_sections.remove(&position);
+            if !self.mesh_section(position) {
+                continue;
+            }
+            for layer in MeshLayer::ALL {
+                let chunk = &self.chunk_ledger[&(position.0, position.2)];
+                // Empty sky and buried sections have nothing to draw, they only get an entity once they do
+                if !chunk.has_section_faces(position.1, layer) && !self.bevy_section_ledger.contains_key(&(position, layer)) {
+                    continue;
+                }
+                let mesh = chunk.create_section_bevy_mesh(position.1, layer).unwrap();
+                match self.bevy_section_ledger.get(&(position, layer)) {
+                    Some(handle) => {
+                        if let Some(existing) = meshes.get_mut(handle) {
+                            *existing = mesh;
+                        }
+                    }
+                    None => self.spawn_bevy_section(position, layer, mesh, commands, meshes, materials)
+                }
+            }
+        }
+    }
+
+    fn spawn_bevy_section(&mut self, position: (i32, i32, i32), layer: MeshLayer, mesh: Mesh, commands: &mut Commands, meshes: &mut ResMut<Assets<Mesh>>, materials: &mut ResMut<Assets<VoxelMaterial>>) {
+        let chunk_position = (position.0, position.2);
+        let parent_chunk = match self.bevy_chunk_ledger.get(&chunk_position) {
+            Some(parent_chunk) => *parent_chunk,
+            None => {
+                let parent_chunk = commands.spawn_bundle(TransformBundle::default()).id();
+                commands.entity(self.terrain_entity.unwrap()).add_child(parent_chunk);
+                self.bevy_chunk_ledger.insert(chunk_position, parent_chunk);
+                parent_chunk
+            }
+        };
+
+        let material = match layer {
+            MeshLayer::Opaque => &self.terrain_material,
+            MeshLayer::Translucent => &self.translucent_material
+        };
+        let handle = meshes.add(mesh);
+        let section = commands.spawn_bundle(MaterialMeshBundle {
+            mesh: handle.clone(),
+            material: materials.get_handle(material),
+            // Placing each section at its origin lets the renderer sort see-through sections back to front
+            transform: Transform::from_translation(section_origin(position)),
+            ..default()
+        }).id();
+        commands.entity(parent_chunk).add_child(section);
+        self.bevy_section_ledger.insert((position, layer), handle);
+    }
+
+    pub fn biome_at(&self, x: i32, z: i32) -> Biome {
+        self.generator.biome_at(x, z)
+    }
+
+    pub fn seed(&self) -> u32 {
+        self.generator.settings().seed
+    }
+
+    /* Saved worlds keep their generator settings and time of day, new ones take them from the settings. Returns the time of day */
+    fn load_metadata(&mut self, settings: &WorldSettings) -> f32 {
+        let mut metadata = WorldMetadata { generator: settings.generator.clone(), ..default() };
+        if let Some(storage) = &self.storage {
+            match storage.load_metadata() {
+                Ok(Some(saved)) => metadata = saved,
+                // A new world, its metadata is written straight away
+                Ok(None) => {}
+                Err(error) => {
+                    error!("Failed to load world metadata, it won't be saved until the file is fixed: {}", error);
+                    self.metadata_unreadable = true;
+                }
             }
-            self.bevy_chunk_ledger.insert(position, parent_chunk);
         }
-        info!("Spawning chunk");
+        self.generator = Arc::new(TerrainGenerator::new(metadata.generator));
+        self.save_metadata(metadata.time_of_day);
+        info!("World seed {}", self.seed());
+        metadata.time_of_day
     }
 
-    fn create_material(&mut self, materials: &mut ResMut<Assets<StandardMaterial>>) {
-        self.terrain_material = materials.add(Color::GREEN.into());
+    pub fn save_metadata(&self, time_of_day: f32) {
+        if self.metadata_unreadable {
+            return;
+        }
+        if let Some(storage) = &self.storage {
+            let metadata = WorldMetadata { generator: self.generator.settings().clone(), time_of_day };
+            if let Err(error) = storage.save_metadata(&metadata) {
+                error!("Failed to save world metadata: {}", error);
+            }
+        }
+    }
+
+    /* Packs the block textures into the atlas the terrain materials sample, see-through blocks share it with a blended material */
+    fn create_material(&mut self, materials: &mut ResMut<Assets<VoxelMaterial>>, images: &mut ResMut<Assets<Image>>) {
+        let (atlas, image) = BlockAtlas::build(BLOCK_TEXTURES_PATH, &self.blocks);
+        self.atlas = atlas;
+        let image = images.add(image);
+        self.terrain_material = materials.add(VoxelMaterial { atlas: image.clone(), alpha_mode: AlphaMode::Opaque });
+        self.translucent_material = materials.add(VoxelMaterial { atlas: image, alpha_mode: AlphaMode::Blend });
     }
 }
 
 pub fn setup_world(
     mut commands: Commands,
     mut world: ResMut<World>,
-    mut materials: ResMut<Assets<StandardMaterial>>
+    settings: Res<WorldSettings>,
+    mut world_time: ResMut<WorldTime>,
+    mut materials: ResMut<Assets<VoxelMaterial>>,
+    mut images: ResMut<Assets<Image>>
 ) {
-    world.terrain_entity = Some(commands.spawn().insert(Terrain).id());
-    world.create_material(&mut materials);
+    world.terrain_entity = Some(commands.spawn_bundle(TransformBundle::default()).insert(Terrain).id());
+    world.storage = settings.save_directory.clone().map(RegionStorage::new);
+    world.meshing_mode = settings.meshing_mode;
+    let time_of_day = world.load_metadata(&settings);
+    world_time.set_time_of_day(time_of_day);
+    world.blocks = BlockRegistry::load(BLOCKS_PATH).unwrap_or_else(|error| panic!("Failed to load block registry: {}", error));
+    world.terrain_blocks = TerrainBlocks::from_registry(&world.blocks, world.generator.settings());
+    world.create_material(&mut materials, &mut images);
 
-    // directional 'sun' light
+    // directional 'sun' light, pointed by the day night cycle
     const HALF_SIZE: f32 = 40.0;
     commands.spawn_bundle(DirectionalLightBundle {
         directional_light: DirectionalLight {
@@ -109,50 +488,183 @@ pub fn setup_world(
                 ..default()
             },
             shadows_enabled: true,
-            illuminance: 10000.,
-            ..default()
-        },
-        transform: Transform {
-            translation: Vec3::new(0.0, 2.0, 0.0),
-            rotation: Quat::from_rotation_x(-std::f32::consts::FRAC_PI_4),
             ..default()
         },
         ..default()
-    });
+    }).insert(Sun);
+}
+
+/* Sections whose meshes depend on the voxel, its own and the ones it borders, since faces, occlusion and smooth light all look
+   into the neighboring voxels */
+pub fn touched_sections(position: (i32, i32, i32)) -> impl Iterator<Item = (i32, i32, i32)> {
+    let length = CHUNK_LENGTH as i32;
+    let axis_offsets = |coordinate: i32| match coordinate.rem_euclid(length) {
+        0 => -1..=0,
+        local if local == length - 1 => 0..=1,
+        _ => 0..=0
+    };
+    let section = (position.0.div_euclid(length), position.1.div_euclid(length), position.2.div_euclid(length));
+    let (x_offsets, y_offsets, z_offsets) = (axis_offsets(position.0), axis_offsets(position.1), axis_offsets(position.2));
+    x_offsets.flat_map(move |x| {
+        let z_offsets = z_offsets.clone();
+        y_offsets.clone().flat_map(move |y| z_offsets.clone().map(move |z| (section.0 + x, section.1 + y, section.2 + z)))
+    })
+}
+
+/* World position of a section's lowest corner */
+pub fn section_origin(position: (i32, i32, i32)) -> Vec3 {
+    Vec3::new(position.0 as f32, position.1 as f32, position.2 as f32) * CHUNK_LENGTH as f32
+}
+
+/* Splits a world voxel position into the chunk holding it and the position inside that chunk */
+pub fn split_voxel_position(position: (i32, i32, i32)) -> ((i32, i32), (u32, i32, u32)) {
+    let length = CHUNK_LENGTH as i32;
+    (
+        (position.0.div_euclid(length), position.2.div_euclid(length)),
+        (position.0.rem_euclid(length) as u32, position.1, position.2.rem_euclid(length) as u32)
+    )
+}
+
+fn chunk_distance(a: (i32, i32), b: (i32, i32)) -> i32 {
+    (a.0 - b.0).abs().max((a.1 - b.1).abs())
 }
 
 pub fn update_world(
     mut commands: Commands,
     mut world: ResMut<World>,
-    mut terrain: Query<Entity, With<Terrain>>,
-    mut loading_pool: Res<AsyncComputeTaskPool>,
-    mut player_query: Query<&Transform, With<PlayerController>>
+    settings: Res<WorldSettings>,
+    loading_pool: Res<AsyncComputeTaskPool>,
+    player_query: Query<&Transform, With<PlayerController>>
 ) {
-    let terrain: Entity = terrain.single();
     let player: &Transform = player_query.single();
     let chunk_x = (player.translation.x / CHUNK_LENGTH as f32).floor() as i32;
     let chunk_z = (player.translation.z / CHUNK_LENGTH as f32).floor() as i32;
+    let load_radius = settings.load_radius;
+    let unload_radius = settings.unload_radius.max(load_radius);
 
-    for i in 0..16 {
-        let min_x = chunk_x - i;
-        let min_z = chunk_z - i;
-        let max_x = chunk_x + i;
-        let max_z = chunk_z + i;
-        for x in min_x..=max_x {
-            for z in min_z..=max_z {
-                world.render_chunk((x, z), &loading_pool);
-            }
-        }
+    world.unload_distant_chunks((chunk_x, chunk_z), unload_radius, &mut commands);
+    world.queue_chunks((chunk_x, chunk_z), load_radius);
+    world.start_generation_tasks(settings.max_generation_tasks, &loading_pool);
+}
+
+/* M switches between greedy and naive meshing to compare them */
+pub fn toggle_meshing_mode(inputs: Res<Input<KeyCode>>, mut world: ResMut<World>) {
+    if inputs.just_pressed(KeyCode::M) {
+        let mode = match world.meshing_mode {
+            MeshingMode::Naive => MeshingMode::Greedy,
+            MeshingMode::Greedy => MeshingMode::Naive
+        };
+        info!("Meshing mode {:?}", mode);
+        world.set_meshing_mode(mode);
     }
-    //info!("X: {}, Z: {}", chunk_x, chunk_z);
-    //info!("PX: {}, PZ: {}", player.translation.x, player.translation.z);
 }
 
 pub fn load_chunks(
     mut world: ResMut<World>,
+    settings: Res<WorldSettings>,
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>
+    mut materials: ResMut<Assets<VoxelMaterial>>
 ) {
-    world.load_chunks(&mut commands, &mut meshes, &mut materials);
-}
\ No newline at end of file
+    world.load_chunks(settings.max_chunk_loads, settings.max_section_meshes, &mut commands, &mut meshes, &mut materials);
+}
+
+/* Runs last in the frame so edits are written out before the app closes */
+pub fn save_world(
+    world: Res<World>,
+    world_time: Res<WorldTime>,
+    mut exit_events: EventReader<AppExit>
+) {
+    if exit_events.iter().next().is_some() {
+        world.save_all_chunks();
+        world.save_metadata(world_time.time_of_day());
+    }
+}
+
+pub fn setup_world_diagnostics(mut diagnostics: ResMut<Diagnostics>) {
+    diagnostics.add(Diagnostic::new(VOXEL_MEMORY, "voxel_memory_kib", 20));
+    diagnostics.add(Diagnostic::new(VOXEL_MEMORY_UNCOMPRESSED, "voxel_memory_uncompressed_kib", 20));
+}
+
+/* Reports voxel storage next to what the same sections would take as plain arrays, logged with the other diagnostics */
+pub fn world_diagnostics(world: Res<World>, mut diagnostics: ResMut<Diagnostics>) {
+    let usage = world.memory_usage();
+    diagnostics.add_measurement(VOXEL_MEMORY, usage.bytes as f64 / 1024.);
+    diagnostics.add_measurement(VOXEL_MEMORY_UNCOMPRESSED, usage.uncompressed_bytes as f64 / 1024.);
+}
+
+#[cfg(test)]
+impl World {
+    /* A world of empty loaded chunks around the origin holding the test blocks and the given voxels */
+    pub(crate) fn with_voxels(voxels: &[((i32, i32, i32), BlockId)]) -> Self {
+        let mut world = World { blocks: crate::game::world::block::test_blocks::registry(), ..default() };
+        for x in -1..=1 {
+            for z in -1..=1 {
+                world.chunk_ledger.insert((x, z), Chunk::new((x, z)));
+            }
+        }
+        for (position, block) in voxels {
+            world.set_voxel(*position, *block);
+        }
+        world
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::world::block::test_blocks::STONE;
+
+    /* Unloads and loads a chunk again, edited chunks go through the region files while the rest are generated the same as before */
+    fn reload(world: &mut World, position: (i32, i32)) {
+        let chunk = world.chunk_ledger.remove(&position).unwrap();
+        let chunk = if chunk.is_edited() { Chunk::deserialize(position, &chunk.serialize()).unwrap() } else { chunk };
+        world.chunk_ledger.insert(position, chunk);
+    }
+
+    #[test]
+    fn unreadable_metadata_is_left_untouched() {
+        let directory = std::env::temp_dir().join(format!("voxel_metadata_test_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("world.ron");
+        std::fs::write(&path, "(generator: (seed: ").unwrap();
+
+        let mut world = World { storage: Some(RegionStorage::new(directory.clone())), ..default() };
+        world.load_metadata(&WorldSettings::default());
+        world.save_metadata(0.5);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(contents, "(generator: (seed: ");
+    }
+
+    #[test]
+    fn blocked_overflow_leaves_the_neighbor_unedited() {
+        let mut world = World::with_voxels(&[]);
+        world.chunk_ledger.get_mut(&(0, 0)).unwrap().set_voxel((15, 5, 3), STONE);
+        let mut source = Chunk::new((1, 0));
+        source.push_overflow((15, 5, 3), STONE);
+        world.chunk_ledger.insert((1, 0), source);
+
+        assert!(world.exchange_overflow((1, 0)).is_empty());
+        assert!(!world.chunk_ledger[&(0, 0)].is_edited());
+    }
+
+    #[test]
+    fn broken_decorations_stay_broken_after_reloading() {
+        let mut world = World::with_voxels(&[]);
+        let mut source = Chunk::new((1, 0));
+        source.push_overflow((15, 5, 3), STONE);
+        world.chunk_ledger.insert((1, 0), source);
+
+        assert_eq!(world.exchange_overflow((1, 0)), vec![(15, 5, 3)]);
+        assert!(world.get_voxel((15, 5, 3)).unwrap().block == STONE);
+        assert!(world.chunk_ledger[&(0, 0)].is_edited() && !world.chunk_ledger[&(1, 0)].is_edited());
+
+        world.set_voxel((15, 5, 3), BlockId::AIR);
+        reload(&mut world, (0, 0));
+        reload(&mut world, (1, 0));
+        world.exchange_overflow((0, 0));
+        world.exchange_overflow((1, 0));
+        assert!(world.get_voxel((15, 5, 3)).unwrap().block.is_air());
+    }
+}